@@ -0,0 +1,171 @@
+//! Compares the throughput of a single-shard cache against sharded caches when many threads log
+//! through the same `RoutingAppender` and every route is already cached. The original design, a
+//! single `Mutex<LinkedHashMap>` purged on every lookup, is included as a baseline.
+//!
+//! Run with `cargo bench --bench cache`.
+extern crate antidote;
+extern crate linked_hash_map;
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+
+use antidote::Mutex;
+use linked_hash_map::LinkedHashMap;
+use log::Record;
+use log4rs::append::Append;
+use log4rs_routing_appender::RoutingAppender;
+use log4rs_routing_appender::route::{Appender, Cache, Route};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROUTES: usize = 64;
+const ITERATIONS: usize = 200_000;
+
+#[derive(Debug)]
+struct NullAppender;
+
+impl Append for NullAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+#[derive(Debug)]
+struct TargetRouter;
+
+impl Route for TargetRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        Ok(cache
+            .entry(record.target().to_owned())
+            .or_insert_with(|| Box::new(NullAppender)))
+    }
+}
+
+struct TrackedAppender {
+    appender: Arc<Box<Append>>,
+    used: Instant,
+}
+
+/// The routing appender as it was before the cache was sharded.
+struct BaselineAppender {
+    cache: Mutex<LinkedHashMap<String, TrackedAppender>>,
+    ttl: Duration,
+}
+
+impl BaselineAppender {
+    fn new() -> BaselineAppender {
+        BaselineAppender {
+            cache: Mutex::new(LinkedHashMap::new()),
+            ttl: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+impl fmt::Debug for BaselineAppender {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BaselineAppender").finish()
+    }
+}
+
+impl Append for BaselineAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<Error + Sync + Send>> {
+        let appender = {
+            let mut cache = self.cache.lock();
+            let now = Instant::now();
+            let timeout = now - self.ttl;
+            loop {
+                match cache.front() {
+                    Some((_, v)) if v.used <= timeout => {}
+                    _ => break,
+                }
+                cache.pop_front();
+            }
+
+            let hit = match cache.get_refresh(record.target()) {
+                Some(entry) => {
+                    entry.used = now;
+                    Some(entry.appender.clone())
+                }
+                None => None,
+            };
+            match hit {
+                Some(appender) => appender,
+                None => {
+                    let appender = Arc::new(Box::new(NullAppender) as Box<Append>);
+                    let entry = TrackedAppender {
+                        appender: appender.clone(),
+                        used: now,
+                    };
+                    cache.insert(record.target().to_owned(), entry);
+                    appender
+                }
+            }
+        };
+        appender.append(record)
+    }
+
+    fn flush(&self) {}
+}
+
+fn run<A>(threads: usize, appender: A) -> Duration
+where
+    A: Append,
+{
+    let appender = Arc::new(appender);
+    let targets = (0..ROUTES).map(|i| i.to_string()).collect::<Vec<_>>();
+    for target in &targets {
+        appender.append(&Record::builder().target(target).build()).unwrap();
+    }
+
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles = (0..threads)
+        .map(|t| {
+            let appender = appender.clone();
+            let targets = targets.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..ITERATIONS {
+                    let target = &targets[(i + t) % ROUTES];
+                    appender.append(&Record::builder().target(target).build()).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn per_record(threads: usize, elapsed: Duration) -> f64 {
+    let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+    nanos as f64 / (threads * ITERATIONS) as f64
+}
+
+fn main() {
+    println!("{:>8} {:>8} {:>14}", "threads", "shards", "ns/record");
+    for &threads in &[1, 2, 4, 8, 16] {
+        let elapsed = run(threads, BaselineAppender::new());
+        println!("{:>8} {:>8} {:>14.1}", threads, "baseline", per_record(threads, elapsed));
+        for &shards in &[1, 4, 16, 64] {
+            let appender = RoutingAppender::builder()
+                .shards(shards)
+                .build(Box::new(TargetRouter));
+            let elapsed = run(threads, appender);
+            println!("{:>8} {:>8} {:>14.1}", threads, shards, per_record(threads, elapsed));
+        }
+    }
+}
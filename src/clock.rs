@@ -0,0 +1,56 @@
+//! Sources of the current time used by the appender cache.
+//!
+//! Idle timeouts and failure backoffs are measured against a `Clock`. By default the system clock
+//! is used, but a `ManualClock` can be supplied to `RoutingAppenderBuilder::clock` so that tests
+//! can control the passage of time.
+use antidote::Mutex;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A source of the current time.
+pub trait Clock: fmt::Debug + Sync + Send + 'static {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+}
+
+/// A `Clock` which returns the system's monotonic time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A `Clock` which only moves forward when it is explicitly advanced.
+///
+/// Clones share the same time, so a clone can be handed to a `RoutingAppender` while the original
+/// is used to advance it.
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl ManualClock {
+    /// Creates a new `ManualClock` starting at the current system time.
+    pub fn new() -> ManualClock {
+        ManualClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// Moves the clock forward by the specified duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock()
+    }
+}
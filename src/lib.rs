@@ -40,12 +40,16 @@ extern crate linked_hash_map;
 extern crate log;
 extern crate log4rs;
 
+#[cfg(feature = "chrono")]
+extern crate chrono;
 #[cfg(feature = "humantime")]
 extern crate humantime;
 #[cfg(feature = "log-mdc")]
 extern crate log_mdc;
 #[cfg(feature = "ordered-float")]
 extern crate ordered_float;
+#[cfg(feature = "regex")]
+extern crate regex;
 #[cfg(feature = "serde")]
 extern crate serde;
 #[cfg(feature = "serde-value")]
@@ -55,12 +59,15 @@ extern crate serde_value;
 #[macro_use]
 extern crate serde_derive;
 
-use antidote::Mutex;
 use log::Record;
 use log4rs::append::Append;
 use std::error::Error;
 use std::fmt;
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "file")]
 use log4rs::file::{Deserialize, Deserializers};
@@ -71,8 +78,10 @@ use serde_value::Value;
 #[cfg(feature = "file")]
 use std::collections::BTreeMap;
 
-use route::{Cache, Route};
+use clock::{Clock, SystemClock};
+use route::{Appender, Cache, CacheStats, EvictionReason, Route};
 
+pub mod clock;
 pub mod route;
 
 /// Configuration for the `RoutingAppender`.
@@ -83,6 +92,7 @@ pub struct RoutingAppenderConfig {
     router: RouterConfig,
     #[serde(default)]
     cache: CacheConfig,
+    fallback: Option<AppenderConfig>,
 }
 
 #[cfg(feature = "file")]
@@ -91,6 +101,12 @@ pub struct RoutingAppenderConfig {
 struct CacheConfig {
     #[serde(deserialize_with = "de_duration", default)]
     idle_timeout: Option<Duration>,
+    max_entries: Option<usize>,
+    #[serde(deserialize_with = "de_duration", default)]
+    failure_backoff: Option<Duration>,
+    #[serde(deserialize_with = "de_duration", default)]
+    reap_interval: Option<Duration>,
+    shards: Option<usize>,
 }
 
 /// Registers the following mappings:
@@ -98,37 +114,152 @@ struct CacheConfig {
 /// * Appenders
 ///     * "routing" -> `RoutingAppenderDeserializer`
 /// * Routers
+///     * "level" -> `LevelRouterDeserializer`
+///     * "map" -> `MapRouterDeserializer`
+///         * Requires the `pattern-router` feature (enabled by default).
+///     * "match" -> `MatchRouterDeserializer`
+///         * Requires the `match-router` feature (enabled by default).
+///     * "multi" -> `MultiRouterDeserializer`
 ///     * "pattern" -> `PatternAppenderDeserializer`
 ///         * Requires the `pattern-router` feature (enabled by default).
+///     * "rate_limit" -> `RateLimitRouterDeserializer`
+///     * "sample" -> `SampleRouterDeserializer`
+///     * "shard" -> `ShardRouterDeserializer`
+///         * Requires the `pattern-router` feature (enabled by default).
+///     * "target" -> `TargetRouterDeserializer`
 #[cfg(feature = "file")]
 pub fn register(d: &mut Deserializers) {
     d.insert("routing", RoutingAppenderDeserializer);
 
+    d.insert("level", route::level::LevelRouterDeserializer);
+    d.insert("multi", route::multi::MultiRouterDeserializer);
+    d.insert("rate_limit", route::rate_limit::RateLimitRouterDeserializer);
+    d.insert("sample", route::sample::SampleRouterDeserializer);
+    d.insert("target", route::target::TargetRouterDeserializer);
+
     #[cfg(feature = "pattern-router")]
     d.insert("pattern", route::pattern::PatternRouterDeserializer);
+    #[cfg(feature = "pattern-router")]
+    d.insert("map", route::pattern::map::MapRouterDeserializer);
+    #[cfg(feature = "pattern-router")]
+    d.insert("shard", route::pattern::shard::ShardRouterDeserializer);
+    #[cfg(feature = "match-router")]
+    d.insert("match", route::matching::MatchRouterDeserializer);
 }
 
 /// An appender which routes log events to dynamically constructed sub-appenders.
 pub struct RoutingAppender {
     router: Box<Route>,
-    cache: Mutex<Cache>,
+    shared: Arc<Shared>,
+    fallback: Option<Box<Append>>,
+    fallback_reported: AtomicBool,
+    // only held to shut the thread down on drop
+    _reaper: Option<Reaper>,
+}
+
+type EvictFn = Fn(&str, &Append, EvictionReason) + Sync + Send;
+
+// State shared with the reaper thread.
+struct Shared {
+    cache: Cache<'static>,
+    on_evict: Option<Box<EvictFn>>,
+}
+
+impl Shared {
+    fn purge(&self) {
+        self.cache.purge_expired();
+        self.release(self.cache.take_evicted());
+    }
+
+    // Evicted appenders are handled after the cache lock has been released since flushing may be
+    // slow.
+    fn release(&self, evicted: Vec<(String, Appender, EvictionReason)>) {
+        for (key, appender, reason) in evicted {
+            appender.appender().flush();
+            if let Some(ref on_evict) = self.on_evict {
+                on_evict(&key, appender.appender(), reason);
+            }
+        }
+    }
+}
+
+struct Reaper {
+    shutdown: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reaper {
+    fn start(shared: Arc<Shared>, interval: Duration) -> Reaper {
+        let (shutdown, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("log4rs-routing-reaper".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                    shared.purge();
+                }
+            })
+            .expect("failed to spawn reaper thread");
+
+        Reaper {
+            shutdown: shutdown,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        let _ = self.shutdown.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
 }
 
 impl fmt::Debug for RoutingAppender {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("RoutingAppender")
             .field("router", &self.router)
+            .field("fallback", &self.fallback)
             .finish()
     }
 }
 
 impl Append for RoutingAppender {
     fn append(&self, record: &Record) -> Result<(), Box<Error + Sync + Send>> {
-        let appender = self.router.route(record, &mut self.cache.lock())?;
-        appender.appender().append(record)
+        let mut appenders = vec![];
+        let routed = self.router.route_all(record, &self.shared.cache, &mut appenders);
+        self.shared.release(self.shared.cache.take_evicted());
+
+        // a failure of one appender shouldn't prevent the others from receiving the log event
+        let mut result = Ok(());
+        for appender in &appenders {
+            if let Err(e) = appender.append(record) {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        match routed {
+            Ok(()) => result,
+            Err(e) => {
+                let fallback = self.append_fallback(record, e);
+                result.and(fallback)
+            }
+        }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        // flushing may be slow, so don't hold the cache's lock while doing it
+        let appenders = self.shared.cache.appenders();
+        for appender in &appenders {
+            appender.appender().flush();
+        }
+        if let Some(ref fallback) = self.fallback {
+            fallback.flush();
+        }
+    }
 }
 
 impl RoutingAppender {
@@ -136,6 +267,94 @@ impl RoutingAppender {
     pub fn builder() -> RoutingAppenderBuilder {
         RoutingAppenderBuilder {
             idle_timeout: Duration::from_secs(2 * 60),
+            max_entries: None,
+            failure_backoff: None,
+            reap_interval: None,
+            shards: 1,
+            clock: Arc::new(SystemClock),
+            on_evict: None,
+            fallback: None,
+        }
+    }
+
+    /// Removes appenders which have exceeded the idle timeout from the cache.
+    ///
+    /// Expired appenders are otherwise only removed when a log event is routed or by the reaper
+    /// thread, if one has been configured.
+    pub fn purge(&self) {
+        self.shared.purge();
+    }
+
+    /// Returns a snapshot of statistics about the appender's cache.
+    pub fn stats(&self) -> CacheStats {
+        self.shared.cache.stats()
+    }
+
+    /// Returns the keys of the appenders currently in the cache, along with the time each was
+    /// last used.
+    ///
+    /// The keys are returned from least to most recently used. Appenders created by routers nested
+    /// within another router are stored under keys prefixed by their scopes, for example
+    /// `0:ERROR` for the `level` router used by the first rule of a `match` router. See
+    /// `Cache::scope` for details. The format of the keys of the `pattern` router is described in
+    /// the `route::pattern` module.
+    pub fn routes(&self) -> Vec<(String, Instant)> {
+        self.shared.cache.routes()
+    }
+
+    /// Removes the appender with the specified key from the cache, returning `true` if it was
+    /// present.
+    ///
+    /// The key must be the full key of the appender, including the prefixes of any scopes it was
+    /// created in, as returned by `routes`. Keys of appenders created by the `pattern` router can
+    /// be built with `route::pattern::route_key`.
+    ///
+    /// The appender is flushed and passed to the eviction callback before being dropped. Any
+    /// remembered construction failure for the key is also forgotten.
+    pub fn evict(&self, key: &str) -> bool {
+        let present = self.shared.cache.evict(key);
+        self.shared.release(self.shared.cache.take_evicted());
+        present
+    }
+
+    /// Removes all appenders whose keys match the provided predicate from the cache, returning
+    /// the number removed.
+    ///
+    /// The appenders are flushed and passed to the eviction callback before being dropped. Any
+    /// remembered construction failures for matching keys are also forgotten.
+    pub fn evict_matching<F>(&self, mut f: F) -> usize
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let count = self.shared.cache.evict_matching(&mut f);
+        self.shared.release(self.shared.cache.take_evicted());
+        count
+    }
+
+    /// Removes all appenders from the cache.
+    ///
+    /// The appenders are flushed and passed to the eviction callback before being dropped. Any
+    /// remembered construction failures are also forgotten.
+    pub fn clear(&self) {
+        self.evict_matching(|_| true);
+    }
+
+    fn append_fallback(
+        &self,
+        record: &Record,
+        error: Box<Error + Sync + Send>,
+    ) -> Result<(), Box<Error + Sync + Send>> {
+        let fallback = match self.fallback {
+            Some(ref fallback) => fallback,
+            None => return Err(error),
+        };
+        fallback.append(record)?;
+        // The record was handled, so only report the first routing error to avoid flooding the
+        // error output while routes are broken.
+        if self.fallback_reported.swap(true, Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(error)
         }
     }
 }
@@ -143,6 +362,13 @@ impl RoutingAppender {
 /// A builder for `RoutingAppender`s.
 pub struct RoutingAppenderBuilder {
     idle_timeout: Duration,
+    max_entries: Option<usize>,
+    failure_backoff: Option<Duration>,
+    reap_interval: Option<Duration>,
+    shards: usize,
+    clock: Arc<Clock>,
+    on_evict: Option<Box<EvictFn>>,
+    fallback: Option<Box<Append>>,
 }
 
 impl RoutingAppenderBuilder {
@@ -155,11 +381,116 @@ impl RoutingAppenderBuilder {
         self
     }
 
+    /// Sets the maximum number of appenders stored in the cache.
+    ///
+    /// When the limit is exceeded, the least recently used appender is removed from the cache. This
+    /// applies in addition to the idle timeout.
+    ///
+    /// Defaults to no limit.
+    pub fn max_entries(mut self, max_entries: usize) -> RoutingAppenderBuilder {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Sets the duration for which a failure to construct an appender will be remembered.
+    ///
+    /// While a failure is remembered, log events routed to the same cache key will immediately
+    /// fail with the original error rather than retrying the construction. If a maximum number of
+    /// entries is set, at most that many failures are remembered, and the oldest are forgotten
+    /// first.
+    ///
+    /// Defaults to no backoff.
+    pub fn failure_backoff(mut self, failure_backoff: Duration) -> RoutingAppenderBuilder {
+        self.failure_backoff = Some(failure_backoff);
+        self
+    }
+
+    /// Sets the interval at which a background thread will remove expired appenders from the
+    /// cache.
+    ///
+    /// Expired appenders are otherwise only removed when a log event is routed, so if logging
+    /// stops, idle appenders and their resources will be held indefinitely. The thread is shut
+    /// down when the `RoutingAppender` is dropped.
+    ///
+    /// Defaults to no background thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the interval is zero.
+    pub fn reap_interval(mut self, reap_interval: Duration) -> RoutingAppenderBuilder {
+        assert!(reap_interval != Duration::new(0, 0), "reap interval must be positive");
+        self.reap_interval = Some(reap_interval);
+        self
+    }
+
+    /// Sets the number of shards the cache is split into.
+    ///
+    /// Each shard has its own lock, so using multiple shards reduces contention when many threads
+    /// log through the appender at once. The maximum number of entries still applies to the cache
+    /// as a whole, but idle appenders are only removed from a shard when it is accessed or purged,
+    /// and evicting an appender to stay within the maximum requires briefly locking every shard.
+    ///
+    /// Defaults to 1.
+    pub fn shards(mut self, shards: usize) -> RoutingAppenderBuilder {
+        self.shards = shards;
+        self
+    }
+
+    /// Sets the clock used to measure idle timeouts and failure backoffs.
+    ///
+    /// Defaults to the system clock. A `ManualClock` can be used to control time in tests.
+    pub fn clock<C>(mut self, clock: C) -> RoutingAppenderBuilder
+    where
+        C: Clock,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets a callback which will be invoked when an appender is removed from the cache.
+    ///
+    /// The callback is passed the full cache key of the appender, including the prefixes of any
+    /// scopes it was created in, the appender itself, and the reason it was removed. Evicted
+    /// appenders are always flushed before the callback is invoked.
+    pub fn on_evict<F>(mut self, on_evict: F) -> RoutingAppenderBuilder
+    where
+        F: Fn(&str, &Append, EvictionReason) + Sync + Send + 'static,
+    {
+        self.on_evict = Some(Box::new(on_evict));
+        self
+    }
+
+    /// Sets an appender which will receive log events that could not be routed.
+    ///
+    /// Without a fallback, routing errors are returned from the `RoutingAppender` and the log
+    /// event is lost. With one, the log event is sent to the fallback instead, and only the first
+    /// routing error is returned.
+    pub fn fallback(mut self, fallback: Box<Append>) -> RoutingAppenderBuilder {
+        self.fallback = Some(fallback);
+        self
+    }
+
     /// Consumes the builder, producing a `RoutingAppender`.
     pub fn build(self, router: Box<Route>) -> RoutingAppender {
+        let cache = Cache::new(
+            self.idle_timeout,
+            self.max_entries,
+            self.failure_backoff,
+            self.shards,
+            self.clock,
+        );
+        let shared = Arc::new(Shared {
+            cache: cache,
+            on_evict: self.on_evict,
+        });
+        let reaper = self.reap_interval.map(|interval| Reaper::start(shared.clone(), interval));
+
         RoutingAppender {
             router: router,
-            cache: Mutex::new(Cache::new(self.idle_timeout)),
+            shared: shared,
+            fallback: self.fallback,
+            fallback_reported: AtomicBool::new(false),
+            _reaper: reaper,
         }
     }
 }
@@ -185,6 +516,34 @@ impl RoutingAppenderBuilder {
 ///   # The duration that a cached appender has been unused after which it
 ///   # will be disposed of. Defaults to 2 minutes.
 ///   idle_timeout: 2 minutes
+///
+///   # The maximum number of appenders to cache. When the limit is exceeded,
+///   # the least recently used appender will be disposed of. Defaults to no
+///   # limit.
+///   max_entries: 100
+///
+///   # The duration for which a failure to construct an appender will be
+///   # remembered. Log events routed to that appender will fail immediately
+///   # until it has passed. At most max_entries failures are remembered.
+///   # Defaults to no backoff.
+///   failure_backoff: 10 seconds
+///
+///   # The interval at which a background thread will dispose of appenders
+///   # which have exceeded the idle timeout. Otherwise, they are only disposed
+///   # of when a log event is routed. Must be positive. Defaults to no
+///   # background thread.
+///   reap_interval: 30 seconds
+///
+///   # The number of independently locked shards the cache is split into. More
+///   # shards reduce lock contention between threads. `max_entries` still
+///   # applies to the whole cache. Defaults to 1.
+///   shards: 16
+///
+/// # An appender which will receive log events that could not be routed, for
+/// # example because an MDC entry was missing. Optional.
+/// fallback:
+///   kind: file
+///   path: "log/unrouted.log"
 /// ```
 #[cfg(feature = "file")]
 pub struct RoutingAppenderDeserializer;
@@ -203,6 +562,24 @@ impl Deserialize for RoutingAppenderDeserializer {
         if let Some(idle_timeout) = config.cache.idle_timeout {
             builder = builder.idle_timeout(idle_timeout);
         }
+        if let Some(max_entries) = config.cache.max_entries {
+            builder = builder.max_entries(max_entries);
+        }
+        if let Some(failure_backoff) = config.cache.failure_backoff {
+            builder = builder.failure_backoff(failure_backoff);
+        }
+        if let Some(reap_interval) = config.cache.reap_interval {
+            if reap_interval == Duration::new(0, 0) {
+                return Err("reap_interval must be positive".into());
+            }
+            builder = builder.reap_interval(reap_interval);
+        }
+        if let Some(shards) = config.cache.shards {
+            builder = builder.shards(shards);
+        }
+        if let Some(fallback) = config.fallback {
+            builder = builder.fallback(deserializers.deserialize(&fallback.kind, fallback.config)?);
+        }
         let router = deserializers.deserialize(&config.router.kind, config.router.config)?;
         Ok(Box::new(builder.build(router)))
     }
@@ -235,6 +612,32 @@ impl<'de> de::Deserialize<'de> for RouterConfig {
     }
 }
 
+#[cfg(feature = "file")]
+struct AppenderConfig {
+    kind: String,
+    config: Value,
+}
+
+#[cfg(feature = "file")]
+impl<'de> de::Deserialize<'de> for AppenderConfig {
+    fn deserialize<D>(d: D) -> Result<AppenderConfig, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let mut map = BTreeMap::<Value, Value>::deserialize(d)?;
+
+        let kind = match map.remove(&Value::String("kind".to_owned())) {
+            Some(kind) => kind.deserialize_into().map_err(|e| e.to_error())?,
+            None => return Err(de::Error::missing_field("kind")),
+        };
+
+        Ok(AppenderConfig {
+            kind: kind,
+            config: Value::Map(map),
+        })
+    }
+}
+
 #[cfg(feature = "file")]
 fn de_duration<'de, D>(d: D) -> Result<Option<Duration>, D::Error>
 where
@@ -274,9 +677,31 @@ where
 }
 
 trait CacheInner {
-    fn new(expiration: Duration) -> Cache;
+    fn new(
+        expiration: Duration,
+        max_entries: Option<usize>,
+        failure_backoff: Option<Duration>,
+        shards: usize,
+        clock: Arc<Clock>,
+    ) -> Cache<'static>;
+
+    fn appenders(&self) -> Vec<Appender>;
+
+    fn take_evicted(&self) -> Vec<(String, Appender, EvictionReason)>;
+
+    fn purge_expired(&self);
+
+    fn stats(&self) -> CacheStats;
+
+    fn routes(&self) -> Vec<(String, Instant)>;
+
+    fn evict(&self, key: &str) -> bool;
+
+    fn evict_matching(&self, f: &mut FnMut(&str) -> bool) -> usize;
 }
 
 trait AppenderInner {
     fn appender(&self) -> &Append;
+
+    fn append(&self, record: &Record) -> Result<(), Box<Error + Sync + Send>>;
 }
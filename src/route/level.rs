@@ -0,0 +1,110 @@
+//! A router which selects an appender based on the level of a log event.
+//!
+//! Each appender is associated with a level threshold. A log event is routed to the appender with
+//! the most severe threshold that the event's level is at least as severe as, or to the default
+//! appender if there is none. For example, with thresholds of `error` and `info`, `ERROR` events
+//! are routed to the first appender, `WARN` and `INFO` events to the second, and `DEBUG` and
+//! `TRACE` events to the default.
+use log::{Level, Record};
+use log4rs::file::{Deserialize, Deserializers};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use AppenderConfig;
+use route::{Appender, Cache, Entry, Route};
+
+/// Configuration for the `LevelRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LevelRouterConfig {
+    levels: BTreeMap<String, AppenderConfig>,
+    default: Option<AppenderConfig>,
+}
+
+/// A router which selects an appender configuration based on the level of a log event.
+pub struct LevelRouter {
+    deserializers: Deserializers,
+    // sorted from most to least severe
+    levels: Vec<(Level, AppenderConfig)>,
+    default: Option<AppenderConfig>,
+}
+
+impl fmt::Debug for LevelRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("LevelRouter").finish()
+    }
+}
+
+impl Route for LevelRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let (key, config) = match self.levels.iter().find(|&&(l, _)| record.level() <= l) {
+            Some(&(level, ref config)) => (level.to_string(), config),
+            None => match self.default {
+                Some(ref config) => ("default".to_owned(), config),
+                None => return Err(format!("no appender for level `{}`", record.level()).into()),
+            },
+        };
+
+        match cache.entry(key) {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => e.insert_with(|| {
+                self.deserializers
+                    .deserialize(&config.kind, config.config.clone())
+            }),
+        }
+    }
+}
+
+/// A deserializer for the `LevelRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: level
+///
+/// # A map of level thresholds to appender configurations. Log events are sent
+/// # to the appender with the most severe threshold they meet. Required.
+/// levels:
+///   error:
+///     kind: file
+///     path: "logs/error.log"
+///
+/// # The appender used for log events which do not meet any threshold.
+/// # Optional.
+/// default:
+///   kind: file
+///   path: "logs/other.log"
+/// ```
+pub struct LevelRouterDeserializer;
+
+impl Deserialize for LevelRouterDeserializer {
+    type Trait = Route;
+    type Config = LevelRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: LevelRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<Route>, Box<Error + Sync + Send>> {
+        let mut levels = vec![];
+        for (level, appender) in config.levels {
+            let level = match level.parse() {
+                Ok(level) => level,
+                Err(_) => return Err(format!("invalid level `{}`", level).into()),
+            };
+            levels.push((level, appender));
+        }
+        levels.sort_by_key(|&(level, _)| level);
+
+        Ok(Box::new(LevelRouter {
+            deserializers: deserializers.clone(),
+            levels: levels,
+            default: config.default,
+        }))
+    }
+}
@@ -0,0 +1,216 @@
+//! A router which delegates to the first of an ordered list of rules matching a log event.
+//!
+//! Each rule consists of a set of conditions and an action. A rule matches a log event if all of
+//! its conditions hold, so a rule without any conditions matches every event. The action is
+//! either a child router, or an appender configuration template which is expanded as by the
+//! `pattern` router. Log events which match no rule cannot be routed.
+//!
+//! Each rule is given its own scope of the appender cache, so rules will never share appenders.
+use log::{Level, Record};
+use log4rs::file::{Deserialize, Deserializers};
+use log_mdc;
+use regex::Regex;
+use serde_value::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+
+use RouterConfig;
+use route::{Appender, Cache, Route};
+use route::pattern::PatternRouterDeserializer;
+
+/// Configuration for a rule of the `MatchRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    level: Option<String>,
+    target: Option<String>,
+    #[serde(default)]
+    mdc_present: Vec<String>,
+    #[serde(default)]
+    mdc: HashMap<String, String>,
+    router: Option<RouterConfig>,
+    appender: Option<Value>,
+}
+
+/// Configuration for the `MatchRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatchRouterConfig {
+    rules: Vec<RuleConfig>,
+}
+
+struct Rule {
+    scope: String,
+    level: Option<Level>,
+    target: Option<String>,
+    mdc_present: Vec<String>,
+    mdc: Vec<(String, Regex)>,
+    router: Box<Route>,
+}
+
+impl Rule {
+    fn matches(&self, record: &Record) -> bool {
+        if let Some(level) = self.level {
+            if record.level() > level {
+                return false;
+            }
+        }
+
+        if let Some(ref target) = self.target {
+            let matches = record.target().starts_with(&**target)
+                && (record.target().len() == target.len()
+                    || record.target()[target.len()..].starts_with("::"));
+            if !matches {
+                return false;
+            }
+        }
+
+        for key in &self.mdc_present {
+            if !log_mdc::get(key, |v| v.is_some()) {
+                return false;
+            }
+        }
+
+        for &(ref key, ref re) in &self.mdc {
+            if !log_mdc::get(key, |v| v.map_or(false, |v| re.is_match(v))) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// A router which delegates to the first rule matching a log event.
+pub struct MatchRouter {
+    rules: Vec<Rule>,
+}
+
+impl fmt::Debug for MatchRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MatchRouter").finish()
+    }
+}
+
+impl MatchRouter {
+    fn find(&self, record: &Record) -> Result<&Rule, Box<Error + Sync + Send>> {
+        match self.rules.iter().find(|r| r.matches(record)) {
+            Some(rule) => Ok(rule),
+            None => Err(format!("no rule matched target `{}`", record.target()).into()),
+        }
+    }
+}
+
+impl Route for MatchRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let rule = self.find(record)?;
+        rule.router.route(record, &cache.scope(&rule.scope))
+    }
+
+    fn route_all(
+        &self,
+        record: &Record,
+        cache: &Cache,
+        appenders: &mut Vec<Appender>,
+    ) -> Result<(), Box<Error + Sync + Send>> {
+        let rule = self.find(record)?;
+        rule.router.route_all(record, &cache.scope(&rule.scope), appenders)
+    }
+}
+
+/// A deserializer for the `MatchRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: match
+///
+/// # The rules to evaluate, in order. Required.
+/// rules:
+///     # Only log events at least as severe as this level match. Optional.
+///   - level: info
+///
+///     # Only log events whose target is this module path or one of its
+///     # submodules match. Optional.
+///     target: server::jobs
+///
+///     # Only log events with all of these MDC keys present match. Optional.
+///     mdc_present:
+///       - job_id
+///
+///     # Only log events whose MDC values match all of these regular expressions
+///     # match. The keys must be present. Optional.
+///     mdc:
+///       job_id: "^[0-9]+$"
+///
+///     # The router used for matching log events. Exactly one of `router` and
+///     # `appender` is required.
+///     router:
+///       kind: pattern
+///       pattern:
+///         kind: file
+///         path: "logs/jobs/${mdc(job_id)}.log"
+///
+///     # A rule without conditions matches every log event. The `appender`
+///     # configuration is a template, as used by the `pattern` router.
+///   - appender:
+///       kind: file
+///       path: "logs/${target}.log"
+/// ```
+pub struct MatchRouterDeserializer;
+
+impl Deserialize for MatchRouterDeserializer {
+    type Trait = Route;
+    type Config = MatchRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: MatchRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<Route>, Box<Error + Sync + Send>> {
+        let mut rules = vec![];
+        for (i, rule) in config.rules.into_iter().enumerate() {
+            let level = match rule.level {
+                Some(level) => match level.parse() {
+                    Ok(level) => Some(level),
+                    Err(_) => return Err(format!("invalid level `{}`", level).into()),
+                },
+                None => None,
+            };
+
+            let mut mdc = vec![];
+            for (key, re) in rule.mdc {
+                mdc.push((key, Regex::new(&re)?));
+            }
+
+            let router = match (rule.router, rule.appender) {
+                (Some(router), None) => deserializers.deserialize(&router.kind, router.config)?,
+                (None, Some(appender)) => {
+                    let mut config = BTreeMap::new();
+                    config.insert(Value::String("pattern".to_owned()), appender);
+                    let config = Value::Map(config).deserialize_into()?;
+                    PatternRouterDeserializer.deserialize(config, deserializers)?
+                }
+                _ => {
+                    return Err("each rule must have exactly one of `router` and `appender`".into())
+                }
+            };
+
+            rules.push(Rule {
+                scope: i.to_string(),
+                level: level,
+                target: rule.target,
+                mdc_present: rule.mdc_present,
+                mdc: mdc,
+                router: router,
+            });
+        }
+
+        Ok(Box::new(MatchRouter { rules: rules }))
+    }
+}
@@ -1,79 +1,668 @@
 //! Routers.
 //!
 //! A router determines the appender to which a log event should be sent.
+use antidote::{Condvar, Mutex};
 use linked_hash_map::LinkedHashMap;
 use log::Record;
 use log4rs::append::Append;
+use std::any::Any;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
 use std::error::Error;
 use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::marker::PhantomData;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
 #[cfg(feature = "file")]
 use log4rs::file::Deserializable;
 
+use clock::Clock;
 use {AppenderInner, CacheInner};
 
+#[cfg(feature = "file")]
+pub mod level;
+#[cfg(feature = "match-router")]
+pub mod matching;
+#[cfg(feature = "file")]
+pub mod multi;
 #[cfg(feature = "pattern-router")]
 pub mod pattern;
+#[cfg(feature = "file")]
+pub mod rate_limit;
+#[cfg(feature = "file")]
+pub mod sample;
+#[cfg(feature = "file")]
+pub mod target;
 
 struct TrackedAppender {
     appender: Appender,
     used: Instant,
+    // set if the appender counts towards the limit of a `Cache::entry_limited` lookup
+    _counted: Option<Counted>,
+}
+
+// A slot reserved in a scope's count of limited appenders, released when dropped.
+struct Counted(Arc<AtomicUsize>);
+
+impl Counted {
+    fn acquire(count: &Arc<AtomicUsize>, limit: usize) -> Option<Counted> {
+        let mut current = count.load(Ordering::SeqCst);
+        loop {
+            if current >= limit {
+                return None;
+            }
+            match count.compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(Counted(count.clone())),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Failure {
+    error: String,
+    time: Instant,
 }
 
 /// A cache of appenders.
 ///
 /// It stores appenders identified by arbitrary strings. It is up to the router to decide how those
 /// strings are formatted.
-pub struct Cache {
+///
+/// The cache is internally synchronized, and its lock is not held while a new appender is being
+/// created. If multiple threads look up the same missing key concurrently, only one will be asked
+/// to create the appender while the others wait for it.
+///
+/// The cache may be split into multiple shards, each with its own lock, to reduce contention
+/// between threads. Keys are assigned to shards by their hash. The maximum number of entries
+/// applies to the cache as a whole, but idle entries are only removed from a shard when it is
+/// accessed or purged.
+///
+/// Routers which delegate to other routers can use `Cache::scope` to give each of them a separate
+/// namespace of keys.
+pub struct Cache<'a>(Inner<'a>);
+
+enum Inner<'a> {
+    Root(Store),
+    // a view whose keys are prefixed with the scope names of it and its parents
+    Scope { parent: &'a Cache<'a>, name: &'a str },
+}
+
+struct Store {
+    shards: Vec<Mutex<State>>,
+    // the number of appenders looked up with `Cache::entry_limited` in each scope
+    counts: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+    hasher: RandomState,
+    max_entries: Option<usize>,
+    // the number of appenders in all shards
+    size: Arc<AtomicUsize>,
+    // set when a shard has evicted appenders waiting to be released
+    evicted: Arc<AtomicBool>,
+    clock: Arc<Clock>,
+}
+
+struct State {
     map: LinkedHashMap<String, TrackedAppender>,
+    pending: HashMap<String, Arc<Pending>>,
     ttl: Duration,
+    size: Arc<AtomicUsize>,
+    evicted: Vec<(String, Appender, EvictionReason)>,
+    has_evicted: Arc<AtomicBool>,
+    clock: Arc<Clock>,
+    failures: LinkedHashMap<String, Failure>,
+    // the number of failures remembered in all shards
+    failure_count: Arc<AtomicUsize>,
+    failure_backoff: Option<Duration>,
+    max_entries: Option<usize>,
+    stats: CacheStats,
+}
+
+// Tracks a construction in progress so that other lookups of the same key can wait for it.
+struct Pending {
+    done: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Pending {
+    fn wait(&self) {
+        let mut done = self.done.lock();
+        while !*done {
+            done = self.cond.wait(done);
+        }
+    }
 }
 
-impl CacheInner for Cache {
-    fn new(ttl: Duration) -> Cache {
-        Cache {
-            map: LinkedHashMap::new(),
-            ttl: ttl,
+impl CacheInner for Cache<'static> {
+    fn new(
+        ttl: Duration,
+        max_entries: Option<usize>,
+        failure_backoff: Option<Duration>,
+        shards: usize,
+        clock: Arc<Clock>,
+    ) -> Cache<'static> {
+        let shards = cmp::max(shards, 1);
+        let evicted = Arc::new(AtomicBool::new(false));
+        let size = Arc::new(AtomicUsize::new(0));
+        let failure_count = Arc::new(AtomicUsize::new(0));
+        let shards = (0..shards)
+            .map(|_| {
+                let state = State {
+                    map: LinkedHashMap::new(),
+                    pending: HashMap::new(),
+                    ttl: ttl,
+                    size: size.clone(),
+                    evicted: vec![],
+                    has_evicted: evicted.clone(),
+                    clock: clock.clone(),
+                    failures: LinkedHashMap::new(),
+                    failure_count: failure_count.clone(),
+                    failure_backoff: failure_backoff,
+                    max_entries: max_entries,
+                    stats: CacheStats::default(),
+                };
+                Mutex::new(state)
+            })
+            .collect();
+
+        Cache(Inner::Root(Store {
+            shards: shards,
+            counts: Mutex::new(HashMap::new()),
+            hasher: RandomState::new(),
+            max_entries: max_entries,
+            size: size,
+            evicted: evicted,
+            clock: clock,
+        }))
+    }
+
+    fn appenders(&self) -> Vec<Appender> {
+        let mut appenders = vec![];
+        for shard in self.store().shards.iter() {
+            let state = shard.lock();
+            appenders.extend(state.map.values().map(|v| v.appender.clone()));
+        }
+        appenders
+    }
+
+    fn take_evicted(&self) -> Vec<(String, Appender, EvictionReason)> {
+        // this is called for every log event, so avoid touching the shards unless necessary
+        let evicted = &self.store().evicted;
+        if !evicted.load(Ordering::Relaxed) || !evicted.swap(false, Ordering::Acquire) {
+            return vec![];
+        }
+
+        let mut evicted = vec![];
+        for shard in self.store().shards.iter() {
+            evicted.append(&mut shard.lock().evicted);
+        }
+        evicted
+    }
+
+    fn purge_expired(&self) {
+        self.store().purge();
+    }
+
+    fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for shard in self.store().shards.iter() {
+            let state = shard.lock();
+            stats.size += state.map.len();
+            stats.hits += state.stats.hits;
+            stats.misses += state.stats.misses;
+            stats.constructions += state.stats.constructions;
+            stats.construction_failures += state.stats.construction_failures;
+            stats.idle_evictions += state.stats.idle_evictions;
+            stats.capacity_evictions += state.stats.capacity_evictions;
+            stats.manual_evictions += state.stats.manual_evictions;
+        }
+        stats
+    }
+
+    fn routes(&self) -> Vec<(String, Instant)> {
+        let mut routes = vec![];
+        for shard in self.store().shards.iter() {
+            let state = shard.lock();
+            routes.extend(state.map.iter().map(|(k, v)| (k.clone(), v.used)));
+        }
+        routes.sort_by_key(|&(_, used)| used);
+        routes
+    }
+
+    fn evict(&self, key: &str) -> bool {
+        self.store().shard(key).lock().evict(key)
+    }
+
+    fn evict_matching(&self, f: &mut FnMut(&str) -> bool) -> usize {
+        let mut count = 0;
+        for shard in self.store().shards.iter() {
+            let mut state = shard.lock();
+            let keys = state
+                .map
+                .keys()
+                .chain(state.failures.keys())
+                .filter(|k| f(k))
+                .cloned()
+                .collect::<Vec<_>>();
+            for key in keys {
+                if state.evict(&key) {
+                    count += 1;
+                }
+            }
         }
+        count
     }
 }
 
-impl Cache {
+impl<'a> Cache<'a> {
     /// Looks up the entry corresponding to the specified key.
-    pub fn entry<'a>(&'a mut self, key: String) -> Entry<'a> {
-        let now = Instant::now();
-        self.purge(now);
+    ///
+    /// If another thread is currently creating the appender for the key, this will block until it
+    /// has finished.
+    pub fn entry<'b>(&'b self, key: String) -> Entry<'b> {
+        let key = self.scoped_key(key);
+        let store = self.store();
+        let shard = store.shard(&key);
+        loop {
+            let pending = {
+                let mut state = shard.lock();
+                match state.lookup(&key) {
+                    Lookup::Hit(appender) => {
+                        return Entry::Occupied(OccupiedEntry(PhantomData, appender));
+                    }
+                    Lookup::Pending(pending) => pending,
+                    Lookup::Miss => {
+                        let pending = state.reserve(key.clone());
+                        return Entry::Vacant(VacantEntry {
+                            store: store,
+                            shard: shard,
+                            key: key,
+                            pending: pending,
+                            counted: None,
+                        });
+                    }
+                }
+            };
+
+            pending.wait();
+        }
+    }
+
+    /// Looks up the entry corresponding to the specified key, unless it is missing and `limit`
+    /// appenders have already been looked up this way in this view's scope.
+    ///
+    /// Only appenders created through this method count towards the limit, including those which
+    /// are still being created. An appender stops counting once it has been evicted, or if its
+    /// construction fails. Reserving a place within the limit is atomic, so concurrent lookups can
+    /// never exceed it.
+    ///
+    /// Returns `None` if the limit has been reached.
+    pub fn entry_limited<'b>(&'b self, key: String, limit: usize) -> Option<Entry<'b>> {
+        let key = self.scoped_key(key);
+        let store = self.store();
+        let shard = store.shard(&key);
+        // looking the key up purges its own shard
+        let mut purged = store.shards.len() == 1;
+        loop {
+            let pending = {
+                let mut state = shard.lock();
+                match state.lookup(&key) {
+                    Lookup::Hit(appender) => {
+                        return Some(Entry::Occupied(OccupiedEntry(PhantomData, appender)));
+                    }
+                    Lookup::Pending(pending) => pending,
+                    Lookup::Miss => {
+                        let count = store.count(self.scoped_key(String::new()));
+                        match Counted::acquire(&count, limit) {
+                            Some(counted) => {
+                                let pending = state.reserve(key.clone());
+                                return Some(Entry::Vacant(VacantEntry {
+                                    store: store,
+                                    shard: shard,
+                                    key: key,
+                                    pending: pending,
+                                    counted: Some(counted),
+                                }));
+                            }
+                            None if purged => return None,
+                            None => {
+                                // idle appenders in other shards count until they're purged
+                                drop(state);
+                                store.purge();
+                                purged = true;
+                                continue;
+                            }
+                        }
+                    }
+                }
+            };
+
+            pending.wait();
+        }
+    }
+
+    /// Returns a view of the cache whose keys are namespaced by the specified scope.
+    ///
+    /// Keys looked up through the returned view are prefixed with the scope followed by a `:`, so
+    /// they will never collide with keys from a different scope. Nested scopes are cumulative, so
+    /// an appender looked up with the key `ERROR` through `cache.scope("0").scope("level")` is
+    /// stored under the key `0:level:ERROR`. The view shares its entries, limits and statistics
+    /// with the rest of the cache.
+    ///
+    /// The view borrows the scope rather than copying it, so creating one is cheap. The prefixed
+    /// key is only built when an entry is looked up.
+    pub fn scope<'b>(&'b self, scope: &'b str) -> Cache<'b> {
+        Cache(Inner::Scope {
+            parent: self,
+            name: scope,
+        })
+    }
 
-        let entry = match self.map.get_refresh(&key) {
-            Some(entry) => {
-                entry.used = now;
-                Some(Appender(entry.appender.0.clone()))
+    /// Returns the current time according to the cache's clock.
+    ///
+    /// Routers which track time should use this rather than `Instant::now` so that they can be
+    /// tested with a `ManualClock`.
+    pub fn now(&self) -> Instant {
+        self.store().clock.now()
+    }
+
+    /// Returns the number of appenders in the cache.
+    ///
+    /// For a scoped view, only the appenders in its scope are counted.
+    pub fn len(&self) -> usize {
+        let shards = self.store().shards.iter();
+        match self.0 {
+            Inner::Root(_) => shards.map(|s| s.lock().map.len()).sum(),
+            Inner::Scope { .. } => {
+                let prefix = self.scoped_key(String::new());
+                shards
+                    .map(|s| s.lock().map.keys().filter(|k| k.starts_with(&prefix)).count())
+                    .sum()
             }
-            None => None,
+        }
+    }
+
+    /// Determines if the cache contains no appenders.
+    ///
+    /// For a scoped view, only the appenders in its scope are considered.
+    pub fn is_empty(&self) -> bool {
+        match self.0 {
+            Inner::Root(ref store) => store.shards.iter().all(|s| s.lock().map.is_empty()),
+            Inner::Scope { .. } => self.len() == 0,
+        }
+    }
+
+    fn store(&self) -> &Store {
+        match self.0 {
+            Inner::Root(ref store) => store,
+            Inner::Scope { parent, .. } => parent.store(),
+        }
+    }
+
+    fn scoped_key(&self, key: String) -> String {
+        match self.0 {
+            Inner::Root(_) => key,
+            Inner::Scope { .. } => {
+                let mut scoped = String::new();
+                self.push_scope(&mut scoped);
+                scoped.push_str(&key);
+                scoped
+            }
+        }
+    }
+
+    fn push_scope(&self, buf: &mut String) {
+        if let Inner::Scope { parent, name } = self.0 {
+            parent.push_scope(buf);
+            buf.push_str(name);
+            buf.push(':');
+        }
+    }
+}
+
+impl Store {
+    fn purge(&self) {
+        for shard in &self.shards {
+            let mut state = shard.lock();
+            let now = state.clock.now();
+            state.purge(now);
+        }
+    }
+
+    fn count(&self, scope: String) -> Arc<AtomicUsize> {
+        self.counts
+            .lock()
+            .entry(scope)
+            .or_insert_with(|| Arc::new(AtomicUsize::new(0)))
+            .clone()
+    }
+
+    // Evicts the least recently used appenders until the cache is within its maximum size.
+    fn evict_overflow(&self) {
+        let max_entries = match self.max_entries {
+            Some(max_entries) => max_entries,
+            None => return,
         };
 
-        match entry {
-            Some(appender) => Entry::Occupied(OccupiedEntry(self, appender)),
-            None => Entry::Vacant(VacantEntry {
-                cache: self,
-                key: key,
-                time: now,
-            }),
+        while self.size.load(Ordering::SeqCst) > max_entries {
+            // each shard is ordered by use, so the oldest appender is at the front of one of them
+            let mut oldest: Option<(usize, Instant)> = None;
+            for (i, shard) in self.shards.iter().enumerate() {
+                if let Some((_, tracked)) = shard.lock().map.front() {
+                    match oldest {
+                        Some((_, used)) if used <= tracked.used => {}
+                        _ => oldest = Some((i, tracked.used)),
+                    }
+                }
+            }
+
+            match oldest {
+                Some((i, _)) => self.shards[i].lock().evict_front(EvictionReason::Capacity),
+                None => break,
+            }
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<State> {
+        if self.shards.len() == 1 {
+            return &self.shards[0];
+        }
+
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        &self.shards[hash as usize % self.shards.len()]
+    }
+}
+
+// The result of looking a key up in a shard.
+enum Lookup {
+    Hit(Appender),
+    Pending(Arc<Pending>),
+    Miss,
+}
+
+impl State {
+    fn lookup(&mut self, key: &str) -> Lookup {
+        let now = self.clock.now();
+        self.purge(now);
+
+        if let Some(entry) = self.map.get_refresh(key) {
+            entry.used = now;
+            let appender = entry.appender.clone();
+            self.stats.hits += 1;
+            return Lookup::Hit(appender);
+        }
+
+        match self.pending.get(key) {
+            Some(pending) => Lookup::Pending(pending.clone()),
+            None => Lookup::Miss,
         }
     }
 
+    // Marks the key as being constructed so that other lookups will wait for it.
+    fn reserve(&mut self, key: String) -> Arc<Pending> {
+        let pending = Arc::new(Pending {
+            done: Mutex::new(false),
+            cond: Condvar::new(),
+        });
+        self.pending.insert(key, pending.clone());
+        pending
+    }
+
     fn purge(&mut self, now: Instant) {
-        let timeout = now - self.ttl;
         loop {
             match self.map.front() {
-                Some((_, v)) if v.used <= timeout => {}
+                Some((_, v)) if expired(v.used, now, self.ttl) => {}
                 _ => break,
             }
-            self.map.pop_front();
+            self.evict_front(EvictionReason::Idle);
+        }
+
+        if let Some(backoff) = self.failure_backoff {
+            loop {
+                match self.failures.front() {
+                    Some((_, f)) if expired(f.time, now, backoff) => {}
+                    _ => break,
+                }
+                self.pop_failure();
+            }
+        }
+    }
+
+    // Remembers a failed construction, forgetting the oldest failures of this shard if the cache
+    // remembers more failures than its maximum number of entries.
+    fn push_failure(&mut self, key: String, failure: Failure) {
+        if self.failures.insert(key, failure).is_none() {
+            self.failure_count.fetch_add(1, Ordering::SeqCst);
+        }
+        if let Some(max_entries) = self.max_entries {
+            while self.failure_count.load(Ordering::SeqCst) > max_entries
+                && !self.failures.is_empty()
+            {
+                self.pop_failure();
+            }
+        }
+    }
+
+    fn pop_failure(&mut self) {
+        if self.failures.pop_front().is_some() {
+            self.failure_count.fetch_sub(1, Ordering::SeqCst);
         }
     }
+
+    // Returns `true` if an appender was removed.
+    fn evict(&mut self, key: &str) -> bool {
+        if self.failures.remove(key).is_some() {
+            self.failure_count.fetch_sub(1, Ordering::SeqCst);
+        }
+        match self.map.remove(key) {
+            Some(tracked) => {
+                self.size.fetch_sub(1, Ordering::SeqCst);
+                self.stats.manual_evictions += 1;
+                self.push_evicted(key.to_owned(), tracked.appender, EvictionReason::Manual);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn evict_front(&mut self, reason: EvictionReason) {
+        if let Some((key, tracked)) = self.map.pop_front() {
+            self.size.fetch_sub(1, Ordering::SeqCst);
+            match reason {
+                EvictionReason::Idle => self.stats.idle_evictions += 1,
+                EvictionReason::Capacity => self.stats.capacity_evictions += 1,
+                EvictionReason::Manual => self.stats.manual_evictions += 1,
+            }
+            self.push_evicted(key, tracked.appender, reason);
+        }
+    }
+
+    fn push_evicted(&mut self, key: String, appender: Appender, reason: EvictionReason) {
+        self.evicted.push((key, appender, reason));
+        self.has_evicted.store(true, Ordering::Release);
+    }
+}
+
+/// A snapshot of statistics about a `Cache`.
+#[derive(Debug, Clone, Default)]
+pub struct CacheStats {
+    size: usize,
+    hits: u64,
+    misses: u64,
+    constructions: u64,
+    construction_failures: u64,
+    idle_evictions: u64,
+    capacity_evictions: u64,
+    manual_evictions: u64,
+}
+
+impl CacheStats {
+    /// Returns the number of appenders in the cache.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of lookups which found an appender in the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Returns the number of lookups which did not find an appender in the cache and attempted
+    /// to create one.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Returns the number of appenders which have been created and inserted into the cache.
+    pub fn constructions(&self) -> u64 {
+        self.constructions
+    }
+
+    /// Returns the number of times the creation of an appender has failed.
+    ///
+    /// Failures remembered due to the failure backoff are not counted again.
+    pub fn construction_failures(&self) -> u64 {
+        self.construction_failures
+    }
+
+    /// Returns the number of appenders removed from the cache for being idle.
+    pub fn idle_evictions(&self) -> u64 {
+        self.idle_evictions
+    }
+
+    /// Returns the number of appenders removed from the cache because it exceeded its maximum
+    /// number of entries.
+    pub fn capacity_evictions(&self) -> u64 {
+        self.capacity_evictions
+    }
+
+    /// Returns the number of appenders explicitly removed from the cache.
+    pub fn manual_evictions(&self) -> u64 {
+        self.manual_evictions
+    }
+}
+
+/// The reason an appender was removed from a `Cache`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// The appender was not used within the idle timeout.
+    Idle,
+    /// The cache exceeded its maximum number of entries, and the appender was the least recently
+    /// used.
+    Capacity,
+    /// The appender was explicitly removed from the cache.
+    Manual,
 }
 
 /// A (possibly vacant) entry of a `Cache`.
@@ -99,7 +688,7 @@ impl<'a> Entry<'a> {
 }
 
 /// An entry which exists in the cache.
-pub struct OccupiedEntry<'a>(&'a mut Cache, Appender);
+pub struct OccupiedEntry<'a>(PhantomData<&'a Store>, Appender);
 
 impl<'a> OccupiedEntry<'a> {
     /// Consumes the entry, returning the associated appender.
@@ -109,42 +698,211 @@ impl<'a> OccupiedEntry<'a> {
 }
 
 /// An entry which does not exist in the cache.
+///
+/// Other lookups of the same key will block until the entry is either inserted or dropped.
 pub struct VacantEntry<'a> {
-    cache: &'a mut Cache,
+    store: &'a Store,
+    shard: &'a Mutex<State>,
     key: String,
-    time: Instant,
+    pending: Arc<Pending>,
+    counted: Option<Counted>,
+}
+
+impl<'a> Drop for VacantEntry<'a> {
+    fn drop(&mut self) {
+        self.shard.lock().pending.remove(&self.key);
+        *self.pending.done.lock() = true;
+        self.pending.cond.notify_all();
+    }
 }
 
 impl<'a> VacantEntry<'a> {
     /// Inserts an appender into the cache, returning the wrapped version of it.
-    pub fn insert(self, value: Box<Append>) -> Appender {
-        let appender = Arc::new(value);
+    pub fn insert(mut self, value: Box<Append>) -> Appender {
+        let appender = {
+            let mut state = self.shard.lock();
+            state.stats.misses += 1;
+            self.store(&mut state, value)
+        };
+        self.store.evict_overflow();
+        appender
+    }
+
+    /// Inserts the appender returned by the provided closure into the cache, returning the
+    /// wrapped version of it.
+    ///
+    /// The closure is called without the cache's lock held.
+    ///
+    /// If the cache has been configured with a failure backoff, errors returned by the closure
+    /// are remembered for that duration. Further calls for the same key during that time will
+    /// return the error again without invoking the closure.
+    pub fn insert_with<F>(mut self, f: F) -> Result<Appender, Box<Error + Sync + Send>>
+    where
+        F: FnOnce() -> Result<Box<Append>, Box<Error + Sync + Send>>,
+    {
+        {
+            let mut state = self.shard.lock();
+            state.stats.misses += 1;
+            if let Some(failure) = state.failures.get(&self.key) {
+                return Err(failure.error.clone().into());
+            }
+        }
+
+        let result = f();
+
+        let mut state = self.shard.lock();
+        match result {
+            Ok(appender) => {
+                let appender = self.store(&mut state, appender);
+                drop(state);
+                self.store.evict_overflow();
+                Ok(appender)
+            }
+            Err(e) => {
+                state.stats.construction_failures += 1;
+                if state.failure_backoff.is_some() {
+                    let failure = Failure {
+                        error: e.to_string(),
+                        time: state.clock.now(),
+                    };
+                    state.push_failure(self.key.clone(), failure);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn store(&mut self, state: &mut State, value: Box<Append>) -> Appender {
+        state.stats.constructions += 1;
+        let appender = Appender {
+            slot: Arc::new(Slot {
+                appender: value,
+                extensions: Mutex::new(vec![]),
+            }),
+            reports: vec![],
+        };
         let tracked = TrackedAppender {
-            appender: Appender(appender.clone()),
-            used: self.time,
+            appender: appender.clone(),
+            used: state.clock.now(),
+            _counted: self.counted.take(),
         };
-        self.cache.map.insert(self.key, tracked);
-        Appender(appender)
+        state.map.insert(self.key.clone(), tracked);
+        state.size.fetch_add(1, Ordering::SeqCst);
+        appender
     }
 }
 
 /// An opaque, wrapped appender stored by the `Cache`.
-pub struct Appender(Arc<Box<Append>>);
+///
+/// Routers can attach state to a cached appender through its extensions. The state lives as long
+/// as the appender, so it is dropped when the appender is evicted from the cache.
+#[derive(Clone)]
+pub struct Appender {
+    slot: Arc<Slot>,
+    reports: Vec<Arc<Report>>,
+}
+
+struct Slot {
+    appender: Box<Append>,
+    extensions: Mutex<Vec<Box<Any + Send>>>,
+}
 
 impl AppenderInner for Appender {
     fn appender(&self) -> &Append {
-        &**self.0
+        &*self.slot.appender
+    }
+
+    fn append(&self, record: &Record) -> Result<(), Box<Error + Sync + Send>> {
+        // a failed report shouldn't prevent the log event itself from being appended
+        let mut result = Ok(());
+        for report in &self.reports {
+            if let Err(e) = report.append(self.appender(), record) {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result.and(self.appender().append(record))
     }
 }
 
+impl Appender {
+    /// Returns the extension of type `T` attached to this appender.
+    ///
+    /// If there is none, the value returned by `f` is attached first. Only one extension of each
+    /// type can be attached, so routers which may be nested within a router of the same kind
+    /// should key the state they store in it by router instance.
+    pub fn extension<T, F>(&self, f: F) -> Arc<T>
+    where
+        T: Any + Sync + Send,
+        F: FnOnce() -> T,
+    {
+        let mut extensions = self.slot.extensions.lock();
+        for extension in extensions.iter() {
+            if let Some(extension) = extension.downcast_ref::<Arc<T>>() {
+                return extension.clone();
+            }
+        }
+
+        let extension = Arc::new(f());
+        extensions.push(Box::new(extension.clone()));
+        extension
+    }
+
+    // Attaches a report to this handle to the appender, which is appended ahead of the log event
+    // being routed.
+    #[cfg(feature = "file")]
+    fn with_report(mut self, report: Arc<Report>) -> Appender {
+        self.reports.push(report);
+        self
+    }
+}
+
+// A log event a router asks to be appended ahead of the one being routed. Routing only selects
+// appenders, and enclosing routers may still discard them, so the `RoutingAppender` appends
+// reports along with the log event itself.
+trait Report: Sync + Send {
+    fn append(&self, appender: &Append, record: &Record) -> Result<(), Box<Error + Sync + Send>>;
+}
+
+// Determines if at least `timeout` has passed between `time` and `now`. This avoids computing
+// `now - timeout`, which an `Instant` can't necessarily represent shortly after the system started.
+fn expired(time: Instant, now: Instant, timeout: Duration) -> bool {
+    time <= now && now.duration_since(time) >= timeout
+}
+
+// Returns an identifier unique to the calling router instance, used to key the state routers store
+// in appender extensions.
+#[cfg(feature = "file")]
+fn router_id() -> usize {
+    #[allow(deprecated)]
+    static NEXT_ID: AtomicUsize = ::std::sync::atomic::ATOMIC_USIZE_INIT;
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 /// A trait implemented by types that can route log events to appenders.
 pub trait Route: fmt::Debug + 'static + Sync + Send {
     /// Returns the appender to which the provided log event should be routed.
     fn route(
         &self,
         record: &Record,
-        cache: &mut Cache,
+        cache: &Cache,
     ) -> Result<Appender, Box<Error + Sync + Send>>;
+
+    /// Adds all of the appenders to which the provided log event should be routed to `appenders`.
+    ///
+    /// Appenders added before an error is returned will still receive the log event.
+    ///
+    /// The default implementation adds the single appender returned by `route`.
+    fn route_all(
+        &self,
+        record: &Record,
+        cache: &Cache,
+        appenders: &mut Vec<Appender>,
+    ) -> Result<(), Box<Error + Sync + Send>> {
+        appenders.push(self.route(record, cache)?);
+        Ok(())
+    }
 }
 
 #[cfg(feature = "file")]
@@ -0,0 +1,158 @@
+//! A router which sends each log event to the appenders selected by several other routers.
+//!
+//! Each child router is given its own scope of the appender cache, so children of the same kind
+//! will never share appenders. A log event is appended to every appender selected by the
+//! children, with the handling of routing failures controlled by the `Policy`.
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::error::Error;
+use std::fmt;
+
+use RouterConfig;
+use route::{Appender, Cache, Route};
+
+/// How the `MultiRouter` handles a child router which fails to route a log event.
+///
+/// When the router fails, the first error is returned, so the log event will be sent to the
+/// `RoutingAppender`'s fallback if one is configured.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Stops at the first failure. The log event is still appended to the appenders selected by
+    /// the children before the one which failed, but the remaining children are not consulted.
+    FailFast,
+    /// Consults every child, appending the log event to all of the appenders which could be
+    /// selected. The router only fails if no appender could be selected at all.
+    BestEffort,
+    /// Consults every child, but only appends the log event if all of them succeed.
+    RequireAll,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::BestEffort
+    }
+}
+
+/// Configuration for the `MultiRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MultiRouterConfig {
+    routers: Vec<RouterConfig>,
+    #[serde(default)]
+    policy: Policy,
+}
+
+/// A router which combines the appenders selected by several child routers.
+pub struct MultiRouter {
+    // each router is paired with the name of its cache scope
+    routers: Vec<(String, Box<Route>)>,
+    policy: Policy,
+}
+
+impl fmt::Debug for MultiRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MultiRouter")
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl Route for MultiRouter {
+    /// Returns the first of the appenders selected by the child routers.
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let mut appenders = vec![];
+        self.route_all(record, cache, &mut appenders)?;
+        match appenders.into_iter().next() {
+            Some(appender) => Ok(appender),
+            None => Err("no appenders selected".into()),
+        }
+    }
+
+    fn route_all(
+        &self,
+        record: &Record,
+        cache: &Cache,
+        appenders: &mut Vec<Appender>,
+    ) -> Result<(), Box<Error + Sync + Send>> {
+        let start = appenders.len();
+        let mut error = None;
+
+        for &(ref scope, ref router) in &self.routers {
+            if let Err(e) = router.route_all(record, &cache.scope(scope), appenders) {
+                if self.policy == Policy::FailFast {
+                    return Err(e);
+                }
+                if error.is_none() {
+                    error = Some(e);
+                }
+            }
+        }
+
+        match error {
+            Some(_) if self.policy == Policy::BestEffort && appenders.len() > start => Ok(()),
+            Some(e) => {
+                if self.policy == Policy::RequireAll {
+                    appenders.truncate(start);
+                }
+                Err(e)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// A deserializer for the `MultiRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: multi
+///
+/// # The routers whose appenders each log event will be sent to. Required.
+/// routers:
+///   - kind: level
+///     levels:
+///       error:
+///         kind: file
+///         path: "logs/errors.log"
+///   - kind: pattern
+///     pattern:
+///       kind: file
+///       path: "logs/${mdc(job_id)}.log"
+///
+/// # How routing failures of individual routers are handled, one of
+/// # `fail_fast`, `best_effort` or `require_all`. Defaults to `best_effort`.
+/// policy: require_all
+/// ```
+pub struct MultiRouterDeserializer;
+
+impl Deserialize for MultiRouterDeserializer {
+    type Trait = Route;
+    type Config = MultiRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: MultiRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<Route>, Box<Error + Sync + Send>> {
+        if config.routers.is_empty() {
+            return Err("at least one router must be specified".into());
+        }
+
+        let mut routers = vec![];
+        for (i, router) in config.routers.into_iter().enumerate() {
+            let router = deserializers.deserialize(&router.kind, router.config)?;
+            routers.push((i.to_string(), router));
+        }
+
+        Ok(Box::new(MultiRouter {
+            routers: routers,
+            policy: config.policy,
+        }))
+    }
+}
@@ -0,0 +1,123 @@
+//! A router which selects an appender from a table keyed by a value of the log event.
+//!
+//! A key template is expanded for each log event as by the `pattern` router, and the result is
+//! looked up in a table of appender configurations. Log events whose key is not in the table, or
+//! whose key could not be expanded because a value was missing, are routed to the default
+//! appender if there is one. Each appender is created the first time it is needed.
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use serde_value::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use AppenderConfig;
+use route::{Appender, Cache, Entry, Route};
+use route::pattern::template::{Sanitize, Template};
+
+/// Configuration for the `MapRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MapRouterConfig {
+    key: String,
+    values: HashMap<String, AppenderConfig>,
+    default: Option<AppenderConfig>,
+}
+
+/// A router which looks appender configurations up by a value of the log event.
+pub struct MapRouter {
+    deserializers: Deserializers,
+    key: Template,
+    values: HashMap<String, AppenderConfig>,
+    default: Option<AppenderConfig>,
+}
+
+impl fmt::Debug for MapRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MapRouter").finish()
+    }
+}
+
+impl Route for MapRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let found = match self.key.expand(&self.key.values(record)) {
+            Ok(Value::String(key)) => match self.values.get(&key) {
+                Some(config) => Ok((format!("value:{}", key), config)),
+                None => Err(format!("no appender for value `{}`", key).into()),
+            },
+            Ok(_) => unreachable!(),
+            Err(e) => Err(e),
+        };
+
+        let (key, config) = match (found, &self.default) {
+            (Ok(found), _) => found,
+            (Err(_), &Some(ref default)) => ("default".to_owned(), default),
+            (Err(e), &None) => return Err(e),
+        };
+
+        match cache.entry(key) {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => e.insert_with(|| {
+                self.deserializers
+                    .deserialize(&config.kind, config.config.clone())
+            }),
+        }
+    }
+}
+
+/// A deserializer for the `MapRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: map
+///
+/// # The template expanded to produce the value looked up in `values`.
+/// # Required.
+/// key: "${mdc(tenant)}"
+///
+/// # A map of values to appender configurations. Required.
+/// values:
+///   acme:
+///     kind: rolling_file
+///     path: "logs/acme.log"
+///     policy:
+///       kind: compound
+///       trigger:
+///         kind: size
+///         limit: 100 mb
+///       roller:
+///         kind: delete
+///   globex:
+///     kind: file
+///     path: "logs/globex.log"
+///
+/// # The appender used for log events whose value is not in `values` or could
+/// # not be determined. Optional.
+/// default:
+///   kind: file
+///   path: "logs/other.log"
+/// ```
+pub struct MapRouterDeserializer;
+
+impl Deserialize for MapRouterDeserializer {
+    type Trait = Route;
+    type Config = MapRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: MapRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<Route>, Box<Error + Sync + Send>> {
+        Ok(Box::new(MapRouter {
+            deserializers: deserializers.clone(),
+            key: Template::new(&Value::String(config.key), Sanitize::None)?,
+            values: config.values,
+            default: config.default,
+        }))
+    }
+}
@@ -2,13 +2,51 @@
 //!
 //! Strings in the configuration template may contain substitution directives. The format is similar
 //! to that of the log4rs pattern encoder, except that it is prefixed with a `$` to avoid conflicts
-//! with patterns in the templated configuration itself. Format specifications are not supported.
+//! with patterns in the templated configuration itself.
 //!
-//! Only one formatter is currently supported:
+//! The following formatters are supported:
 //!
 //! * `mdc` - An entry from the [MDC][MDC]. The first argument is required, and specifies the key to
 //!     look up. If the key is not present, an error is raised. A second, optional argument allows
 //!     a replacement string to be used if the key is not present.
+//! * `level` - The level of the log event.
+//! * `target` - The target of the log event.
+//! * `module` - The module path of the log event. If it is not present, an error is raised. An
+//!     optional argument allows a replacement string to be used instead.
+//! * `file` - The source file of the log event. If it is not present, an error is raised. An
+//!     optional argument allows a replacement string to be used instead.
+//! * `line` - The source line of the log event. If it is not present, an error is raised. An
+//!     optional argument allows a replacement string to be used instead.
+//! * `d`, `date` - The current date. The first argument optionally specifies a
+//!     [chrono format string][chrono], defaulting to `%Y-%m-%d`. A second, optional argument
+//!     specifies the timezone, either `local` (the default) or `utc`. Since a separate appender is
+//!     created for each distinct date, this can be used to start a new file periodically, for
+//!     example `${d(%Y-%m-%d-%H)}` for one each hour. The format should be coarse - one including
+//!     seconds or fractions of a second will create a new appender for nearly every log event.
+//! * `env` - An environment variable. The first argument is required, and specifies the variable to
+//!     look up. If the variable is not present, an error is raised. A second, optional argument
+//!     allows a replacement string to be used if the variable is not present. Environment
+//!     variables are read once, when the configuration is loaded.
+//!
+//! A separate appender is created for each distinct combination of substituted values.
+//!
+//! Substitutions may be followed by modifiers, each prefixed with a `:`. Only one named modifier is
+//! currently supported:
+//!
+//! * `path` - Escapes the substituted value so that it can safely be used as a single path
+//!     component. Path separators, control characters and `%` are percent-encoded, as are the
+//!     values `.` and `..`. For example, `${mdc(job_id):path}` will substitute `..%2Fetc` for a
+//!     job ID of `../etc`.
+//!
+//! A modifier may also be a format specification, as in the log4rs pattern encoder. It has the
+//! form `[[fill]align][min][.max]`, where `align` is `<` for left alignment (the default) or `>`
+//! for right alignment, and `fill` is the character used for padding, defaulting to a space.
+//! Values shorter than `min` characters are padded, and values longer than `max` characters are
+//! truncated. Left aligned values are truncated from the end, and right aligned values from the
+//! start. For example, `${mdc(job_id):.16}` substitutes at most the first 16 characters of the job
+//! ID, and `${line(0):0>5}` pads line numbers with zeros. Formatting is applied before
+//! sanitization, so the widths apply to the original value, and the substituted text may be
+//! longer than `max` if characters were escaped.
 //!
 //! # Examples
 //!
@@ -33,17 +71,44 @@
 //! path: "logs/sfackler/no_job.log"
 //! ```
 //!
+//! # Cache keys
+//!
+//! Each appender is stored in the cache under a key built from its substituted values, which is
+//! the key returned by `RoutingAppender::routes` and accepted by `RoutingAppender::evict`. For each
+//! distinct substitution in the template, the key contains the length of the substituted value in
+//! bytes followed by the value itself, after formatting and sanitization, or `-` if the value is
+//! missing. Substitutions are ordered by their first appearance in the template, visiting the
+//! entries of maps in order of their keys. Environment variables are not part of the key.
+//! `route_key` builds a key from a list of values.
+//!
+//! For example, the appender created above is stored under the key `8sfackler6no_job`, so it can
+//! be closed once the job has finished with
+//!
+//! ```
+//! # extern crate log4rs_routing_appender;
+//! # use log4rs_routing_appender::RoutingAppender;
+//! use log4rs_routing_appender::route::pattern::route_key;
+//!
+//! # fn job_finished(appender: &RoutingAppender) {
+//! appender.evict(&route_key(vec![Some("sfackler"), Some("no_job")]));
+//! # }
+//! # fn main() {}
+//! ```
+//!
 //! [MDC]: https://crates.io/crates/log-mdc
+//! [chrono]: https://docs.rs/chrono/0.4/chrono/format/strftime/index.html
 use log4rs::file::{Deserialize, Deserializers};
 use log::Record;
-use serde::de;
-use serde_value::Value;
-use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
+use std::fmt::Write;
 
+use AppenderConfig;
 use route::{Appender, Cache, Entry, Route};
-use route::pattern::template::Template;
+use route::pattern::template::{Sanitize, Template, Values};
+
+pub mod map;
+pub mod shard;
 
 mod parser;
 mod template;
@@ -53,6 +118,10 @@ mod template;
 #[serde(deny_unknown_fields)]
 pub struct PatternRouterConfig {
     pattern: AppenderConfig,
+    #[serde(default)]
+    sanitize: Sanitize,
+    max_routes: Option<usize>,
+    overflow: Option<String>,
 }
 
 /// A router which expands an appender configuration template.
@@ -60,6 +129,14 @@ pub struct PatternRouter {
     deserializers: Deserializers,
     kind: String,
     config: Template,
+    max_routes: Option<usize>,
+    overflow: Option<Overflow>,
+}
+
+// The substitutions used once the route limit has been reached.
+struct Overflow {
+    values: Values,
+    key: String,
 }
 
 impl fmt::Debug for PatternRouter {
@@ -69,18 +146,53 @@ impl fmt::Debug for PatternRouter {
 }
 
 impl Route for PatternRouter {
-    fn route(&self, _: &Record, cache: &mut Cache) -> Result<Appender, Box<Error + Sync + Send>> {
-        match cache.entry(self.config.key()) {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let values = self.config.values(record);
+
+        let (entry, values) = match self.max_routes {
+            Some(max_routes) => match cache.entry_limited(values.key(), max_routes) {
+                Some(entry) => (entry, &values),
+                // the overflow route is looked up normally so it doesn't take up one of the routes
+                None => match self.overflow {
+                    Some(ref overflow) => (cache.entry(overflow.key.clone()), &overflow.values),
+                    None => return Err(format!("route limit of {} reached", max_routes).into()),
+                },
+            },
+            None => (cache.entry(values.key()), &values),
+        };
+
+        match entry {
             Entry::Occupied(e) => Ok(e.into_value()),
-            Entry::Vacant(e) => {
-                let appender = self.deserializers
-                    .deserialize(&self.kind, self.config.expand()?)?;
-                Ok(e.insert(appender))
-            }
+            Entry::Vacant(e) => e.insert_with(|| {
+                self.deserializers
+                    .deserialize(&self.kind, self.config.expand(values)?)
+            }),
         }
     }
 }
 
+/// Returns the cache key of the appender created for the specified substituted values.
+///
+/// See the module documentation for how values are ordered. A `None` value represents a
+/// substitution which is missing.
+pub fn route_key<'a, I>(values: I) -> String
+where
+    I: IntoIterator<Item = Option<&'a str>>,
+{
+    let mut key = String::new();
+    for value in values {
+        match value {
+            Some(v) => write!(key, "{}{}", v.len(), v).unwrap(),
+            None => key.push('-'),
+        }
+    }
+    key
+}
+
 /// A deserializer for the `PatternRouter`.
 ///
 /// # Configuration
@@ -92,6 +204,21 @@ impl Route for PatternRouter {
 /// pattern:
 ///   kind: file
 ///   path: "logs/${mdc(user_id)}/${mdc(job_id)(no_job)}.log"
+///
+/// # How substituted values are sanitized, either `none` or `path`. With
+/// # `path`, every substitution behaves as if it had the `path` modifier.
+/// # Defaults to `none`.
+/// sanitize: path
+///
+/// # The maximum number of distinct routes which may be cached at once. Routes
+/// # still being created count towards the limit. Defaults to no limit.
+/// max_routes: 100
+///
+/// # The value substituted for every directive in the template when a new route
+/// # would exceed `max_routes`. The overflow route does not count towards
+/// # `max_routes`. If not set, log events for new routes will fail instead.
+/// # Optional.
+/// overflow: other
 /// ```
 pub struct PatternRouterDeserializer;
 
@@ -104,34 +231,21 @@ impl Deserialize for PatternRouterDeserializer {
         config: PatternRouterConfig,
         deserializers: &Deserializers,
     ) -> Result<Box<Route>, Box<Error + Sync + Send>> {
+        let template = Template::new(&config.pattern.config, config.sanitize)?;
+        let overflow = config.overflow.map(|overflow| {
+            let values = template.overflow_values(&overflow);
+            Overflow {
+                key: values.key(),
+                values: values,
+            }
+        });
+
         Ok(Box::new(PatternRouter {
             deserializers: deserializers.clone(),
             kind: config.pattern.kind,
-            config: Template::new(&config.pattern.config)?,
+            config: template,
+            max_routes: config.max_routes,
+            overflow: overflow,
         }))
     }
 }
-
-struct AppenderConfig {
-    kind: String,
-    config: Value,
-}
-
-impl<'de> de::Deserialize<'de> for AppenderConfig {
-    fn deserialize<D>(d: D) -> Result<AppenderConfig, D::Error>
-    where
-        D: de::Deserializer<'de>,
-    {
-        let mut map = BTreeMap::<Value, Value>::deserialize(d)?;
-
-        let kind = match map.remove(&Value::String("kind".to_owned())) {
-            Some(kind) => kind.deserialize_into().map_err(|e| e.to_error())?,
-            None => return Err(de::Error::missing_field("kind")),
-        };
-
-        Ok(AppenderConfig {
-            kind: kind,
-            config: Value::Map(map),
-        })
-    }
-}
@@ -3,10 +3,90 @@ use std::str::CharIndices;
 
 pub enum Piece<'a> {
     Text(&'a str),
-    Argument { name: &'a str, args: Vec<&'a str> },
+    Argument {
+        name: &'a str,
+        args: Vec<&'a str>,
+        modifiers: Vec<Modifier<'a>>,
+    },
     Error(&'static str),
 }
 
+pub enum Modifier<'a> {
+    Name(&'a str),
+    Format(Format),
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Alignment {
+    Left,
+    Right,
+}
+
+// A format specification of the form `[[fill]align][min][.max]`.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct Format {
+    pub fill: char,
+    pub align: Alignment,
+    pub min: Option<usize>,
+    pub max: Option<usize>,
+}
+
+impl Format {
+    fn parse(s: &str) -> Option<Format> {
+        let mut format = Format {
+            fill: ' ',
+            align: Alignment::Left,
+            min: None,
+            max: None,
+        };
+
+        let alignment = |c| match c {
+            '<' => Some(Alignment::Left),
+            '>' => Some(Alignment::Right),
+            _ => None,
+        };
+        let mut chars = s.chars();
+        let first = chars.next();
+        let rest = match (first, chars.next().and_then(alignment)) {
+            (Some(fill), Some(align)) => {
+                format.fill = fill;
+                format.align = align;
+                &s[fill.len_utf8() + 1..]
+            }
+            _ => match first.and_then(alignment) {
+                Some(align) => {
+                    format.align = align;
+                    &s[1..]
+                }
+                None => s,
+            },
+        };
+
+        let width = |s: &str| {
+            if !s.is_empty() && s.bytes().all(|b| b >= b'0' && b <= b'9') {
+                s.parse().ok()
+            } else {
+                None
+            }
+        };
+        let min = match rest.find('.') {
+            Some(idx) => {
+                format.max = Some(width(&rest[idx + 1..])?);
+                &rest[..idx]
+            }
+            None => rest,
+        };
+        if !min.is_empty() {
+            format.min = Some(width(min)?);
+        }
+
+        if format.min.is_none() && format.max.is_none() {
+            return None;
+        }
+        Some(format)
+    }
+}
+
 pub struct Parser<'a> {
     pattern: &'a str,
     it: Peekable<CharIndices<'a>>,
@@ -39,12 +119,14 @@ impl<'a> Parser<'a> {
             Ok(args) => args,
             Err(e) => return Piece::Error(e),
         };
+        let modifiers = self.modifiers();
         if !self.consume('}') {
             return Piece::Error("expected `}`");
         }
         Piece::Argument {
             name: name,
             args: args,
+            modifiers: modifiers,
         }
     }
 
@@ -96,6 +178,35 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn modifiers(&mut self) -> Vec<Modifier<'a>> {
+        let mut modifiers = vec![];
+        while self.consume(':') {
+            let modifier = self.modifier();
+            match Format::parse(modifier) {
+                Some(format) => modifiers.push(Modifier::Format(format)),
+                None => modifiers.push(Modifier::Name(modifier)),
+            }
+        }
+        modifiers
+    }
+
+    fn modifier(&mut self) -> &'a str {
+        let start = match self.it.peek() {
+            Some(&(pos, _)) => pos,
+            None => return "",
+        };
+
+        loop {
+            match self.it.peek() {
+                Some(&(end, ':')) | Some(&(end, '}')) => return &self.pattern[start..end],
+                Some(_) => {
+                    self.it.next();
+                }
+                None => return &self.pattern[start..],
+            }
+        }
+    }
+
     fn text(&mut self, start: usize) -> Piece<'a> {
         while let Some(&(pos, ch)) = self.it.peek() {
             match ch {
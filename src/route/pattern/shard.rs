@@ -0,0 +1,134 @@
+//! A router which spreads log events across a fixed number of appenders.
+//!
+//! A key template is expanded for each log event and hashed into one of a configured number of
+//! shards. The appender configuration template is then expanded as by the `pattern` router, with
+//! the additional `shard` formatter substituting the shard number, from `0` up to but excluding the
+//! number of shards. Log events with the same key are always sent to the same shard, so this can
+//! be used to group the log events of an unbounded number of tenants into a bounded number of
+//! files.
+//!
+//! The hash of a key does not depend on the process, so keys will be assigned to the same shards
+//! across restarts as long as the number of shards does not change.
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use serde_value::Value;
+use std::error::Error;
+use std::fmt;
+
+use AppenderConfig;
+use route::{Appender, Cache, Entry, Route};
+use route::pattern::template::{Sanitize, Template};
+
+/// Configuration for the `ShardRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShardRouterConfig {
+    key: String,
+    shards: u64,
+    pattern: AppenderConfig,
+    #[serde(default)]
+    sanitize: Sanitize,
+}
+
+/// A router which hashes log events into a fixed number of appenders.
+pub struct ShardRouter {
+    deserializers: Deserializers,
+    key: Template,
+    shards: u64,
+    kind: String,
+    config: Template,
+}
+
+impl fmt::Debug for ShardRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ShardRouter")
+            .field("shards", &self.shards)
+            .finish()
+    }
+}
+
+impl Route for ShardRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let key = match self.key.expand(&self.key.values(record))? {
+            Value::String(key) => key,
+            _ => unreachable!(),
+        };
+        let shard = fnv1a(key.as_bytes()) % self.shards;
+
+        let values = self.config.values_with(record, &[shard.to_string()]);
+        match cache.entry(values.key()) {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => e.insert_with(|| {
+                self.deserializers
+                    .deserialize(&self.kind, self.config.expand(&values)?)
+            }),
+        }
+    }
+}
+
+// The 64 bit FNV-1a hash. Unlike the standard library's hashers, it is guaranteed to be stable.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// A deserializer for the `ShardRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: shard
+///
+/// # The template expanded to produce the key which is hashed. Required.
+/// key: "${mdc(tenant)(none)}"
+///
+/// # The number of shards. Required.
+/// shards: 16
+///
+/// # The configuration template to expand. `${shard}` is replaced by the shard
+/// # number. Required.
+/// pattern:
+///   kind: file
+///   path: "logs/shard-${shard}.log"
+///
+/// # How substituted values in `pattern` are sanitized, either `none` or
+/// # `path`. Defaults to `none`.
+/// sanitize: path
+/// ```
+pub struct ShardRouterDeserializer;
+
+impl Deserialize for ShardRouterDeserializer {
+    type Trait = Route;
+    type Config = ShardRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: ShardRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<Route>, Box<Error + Sync + Send>> {
+        if config.shards == 0 {
+            return Err("the number of shards must be positive".into());
+        }
+
+        Ok(Box::new(ShardRouter {
+            deserializers: deserializers.clone(),
+            key: Template::new(&Value::String(config.key), Sanitize::None)?,
+            shards: config.shards,
+            kind: config.pattern.kind,
+            config: Template::with_variables(
+                &config.pattern.config,
+                config.sanitize,
+                &["shard"],
+            )?,
+        }))
+    }
+}
+
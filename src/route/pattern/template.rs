@@ -1,52 +1,370 @@
+use chrono::{Local, Utc};
+use chrono::format::{Item, StrftimeItems};
 use serde_value::Value;
 use ordered_float::OrderedFloat;
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::BTreeMap;
+use std::env;
 use std::error::Error;
 use std::fmt::Write;
+use log::Record;
 use log_mdc;
 
-use route::pattern::parser::{Parser, Piece};
+use route::pattern::parser::{Alignment, Format, Modifier, Parser, Piece};
+use route::pattern::route_key;
 
 pub struct Template {
     value: ValueTemplate,
-    keys: HashSet<String>,
+    args: Vec<Arg>,
 }
 
 impl Template {
-    pub fn new(pattern: &Value) -> Result<Template, Box<Error + Sync + Send>> {
-        let value = ValueTemplate::new(pattern)?;
-        let mut keys = HashSet::new();
-        value.keys(&mut keys);
+    pub fn new(pattern: &Value, sanitize: Sanitize) -> Result<Template, Box<Error + Sync + Send>> {
+        Template::with_variables(pattern, sanitize, &[])
+    }
+
+    // Variables are substituted like arguments without parameters, but their values are supplied
+    // by the router through `values_with`.
+    pub fn with_variables(
+        pattern: &Value,
+        sanitize: Sanitize,
+        variables: &[&str],
+    ) -> Result<Template, Box<Error + Sync + Send>> {
+        let mut builder = Builder {
+            args: vec![],
+            sanitize: sanitize,
+            variables: variables.iter().map(|&v| v.to_owned()).collect(),
+        };
+        let value = ValueTemplate::new(pattern, &mut builder)?;
         Ok(Template {
             value: value,
-            keys: keys,
+            args: builder.args,
         })
     }
 
+    pub fn values(&self, record: &Record) -> Values {
+        self.values_with(record, &[])
+    }
+
+    pub fn values_with(&self, record: &Record, variables: &[String]) -> Values {
+        Values(self.args.iter().map(|a| a.value(record, variables)).collect())
+    }
+
+    pub fn overflow_values(&self, overflow: &str) -> Values {
+        Values(
+            self.args
+                .iter()
+                .map(|a| Some(a.finish(overflow.to_owned())))
+                .collect(),
+        )
+    }
+
+    pub fn expand(&self, values: &Values) -> Result<Value, Box<Error + Sync + Send>> {
+        self.value.expand(&self.args, values)
+    }
+}
+
+pub struct Values(Vec<Option<String>>);
+
+impl Values {
     pub fn key(&self) -> String {
-        let mut s = String::new();
-        for key in &self.keys {
-            log_mdc::get(key, |k| match k {
-                Some(k) => write!(s, "{}{}", k.len(), k).unwrap(),
-                None => s.push('-'),
-            });
+        route_key(self.0.iter().map(|v| v.as_ref().map(|v| &**v)))
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sanitize {
+    None,
+    Path,
+}
+
+impl Default for Sanitize {
+    fn default() -> Sanitize {
+        Sanitize::None
+    }
+}
+
+impl Sanitize {
+    fn apply(self, value: String) -> String {
+        match self {
+            Sanitize::None => value,
+            Sanitize::Path => sanitize_path(value),
         }
-        s
     }
+}
 
-    pub fn expand(&self) -> Result<Value, Box<Error + Sync + Send>> {
-        self.value.expand()
+// Escapes anything that could change the structure of a path. Escaping is done in the style of
+// percent-encoding rather than by replacement so that distinct values stay distinct.
+fn sanitize_path(value: String) -> String {
+    if value == "." || value == ".." {
+        return value.replace('.', "%2E");
+    }
+
+    let unsafe_char = |c: char| c == '/' || c == '\\' || c == '%' || c.is_control();
+    if !value.contains(unsafe_char) {
+        return value;
+    }
+
+    let mut s = String::with_capacity(value.len());
+    for c in value.chars() {
+        if unsafe_char(c) {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                write!(s, "%{:02X}", b).unwrap();
+            }
+        } else {
+            s.push(c);
+        }
+    }
+    s
+}
+
+struct Builder {
+    args: Vec<Arg>,
+    sanitize: Sanitize,
+    variables: Vec<String>,
+}
+
+impl Builder {
+    fn chunk(&mut self, piece: Piece, s: &str) -> Result<Chunk, Box<Error + Sync + Send>> {
+        let (name, params, modifiers) = match piece {
+            Piece::Text(t) => return Ok(Chunk::Text(t.to_owned())),
+            Piece::Argument {
+                name,
+                args,
+                modifiers,
+            } => (name, args, modifiers),
+            Piece::Error(e) => return Err(format!("{}: `{}`", e, s).into()),
+        };
+
+        let mut sanitize = self.sanitize;
+        let mut format = None;
+        for modifier in modifiers {
+            match modifier {
+                Modifier::Name("path") => sanitize = Sanitize::Path,
+                Modifier::Name(modifier) => {
+                    return Err(format!("unknown modifier `{}`: `{}`", modifier, s).into())
+                }
+                Modifier::Format(f) => {
+                    if format.is_some() {
+                        return Err(format!("multiple format specifications: `{}`", s).into());
+                    }
+                    format = Some(f);
+                }
+            }
+        }
+
+        // environment variables are resolved up front rather than per log event
+        if name == "env" {
+            let value = apply_format(format, env_var(&params, s)?);
+            return Ok(Chunk::Text(sanitize.apply(value)));
+        }
+
+        let arg = match self.variables.iter().position(|v| v == name) {
+            Some(idx) => {
+                if !params.is_empty() {
+                    return Err(format!("expected no arguments: `{}`", s).into());
+                }
+                Arg {
+                    source: Source::Variable(idx, name.to_owned()),
+                    default: None,
+                    sanitize: sanitize,
+                    format: format,
+                }
+            }
+            None => Arg::new(name, &params, sanitize, format, s)?,
+        };
+        match self.args.iter().position(|a| *a == arg) {
+            Some(idx) => Ok(Chunk::Arg(idx)),
+            None => {
+                self.args.push(arg);
+                Ok(Chunk::Arg(self.args.len() - 1))
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum Source {
+    Mdc(String),
+    Level,
+    Target,
+    Module,
+    File,
+    Line,
+    Date { format: String, utc: bool },
+    // an index into the variables supplied by the router, and the variable's name
+    Variable(usize, String),
+}
+
+#[derive(PartialEq, Eq)]
+struct Arg {
+    source: Source,
+    default: Option<String>,
+    sanitize: Sanitize,
+    format: Option<Format>,
+}
+
+impl Arg {
+    fn new(
+        name: &str,
+        params: &[&str],
+        sanitize: Sanitize,
+        format: Option<Format>,
+        s: &str,
+    ) -> Result<Arg, Box<Error + Sync + Send>> {
+        let (source, default) = match name {
+            "mdc" => {
+                if params.is_empty() || params.len() > 2 {
+                    return Err(format!("expected 1 or 2 arguments: `{}`", s).into());
+                }
+                (Source::Mdc(params[0].to_owned()), params.get(1))
+            }
+            "level" | "target" => {
+                if !params.is_empty() {
+                    return Err(format!("expected no arguments: `{}`", s).into());
+                }
+                let source = if name == "level" {
+                    Source::Level
+                } else {
+                    Source::Target
+                };
+                (source, None)
+            }
+            "module" | "file" | "line" => {
+                if params.len() > 1 {
+                    return Err(format!("expected 0 or 1 arguments: `{}`", s).into());
+                }
+                let source = match name {
+                    "module" => Source::Module,
+                    "file" => Source::File,
+                    _ => Source::Line,
+                };
+                (source, params.first())
+            }
+            "d" | "date" => {
+                if params.len() > 2 {
+                    return Err(format!("expected 0, 1, or 2 arguments: `{}`", s).into());
+                }
+                let format = match params.first() {
+                    Some(&format) => format,
+                    None => "%Y-%m-%d",
+                };
+                if StrftimeItems::new(format).any(|i| i == Item::Error) {
+                    return Err(format!("invalid date format `{}`: `{}`", format, s).into());
+                }
+                let utc = match params.get(1) {
+                    Some(&"utc") => true,
+                    Some(&"local") | None => false,
+                    Some(tz) => return Err(format!("invalid timezone `{}`: `{}`", tz, s).into()),
+                };
+                let source = Source::Date {
+                    format: format.to_owned(),
+                    utc: utc,
+                };
+                (source, None)
+            }
+            _ => return Err(format!("unknown argument `{}`: `{}`", name, s).into()),
+        };
+
+        Ok(Arg {
+            source: source,
+            default: default.map(|&s| s.to_owned()),
+            sanitize: sanitize,
+            format: format,
+        })
+    }
+
+    fn value(&self, record: &Record, variables: &[String]) -> Option<String> {
+        let value = match self.source {
+            Source::Mdc(ref key) => log_mdc::get(key, |v| v.map(|v| v.to_owned())),
+            Source::Level => Some(record.level().to_string()),
+            Source::Target => Some(record.target().to_owned()),
+            Source::Module => record.module_path().map(|m| m.to_owned()),
+            Source::File => record.file().map(|f| f.to_owned()),
+            Source::Line => record.line().map(|l| l.to_string()),
+            Source::Date { ref format, utc } => {
+                let date = if utc {
+                    Utc::now().format(format).to_string()
+                } else {
+                    Local::now().format(format).to_string()
+                };
+                Some(date)
+            }
+            Source::Variable(idx, _) => variables.get(idx).cloned(),
+        };
+        value
+            .or_else(|| self.default.clone())
+            .map(|v| self.finish(v))
+    }
+
+    // Values are formatted before they are sanitized so that truncation can't produce `.` or `..`,
+    // or split an escape sequence.
+    fn finish(&self, value: String) -> String {
+        self.sanitize.apply(apply_format(self.format, value))
+    }
+
+    fn missing(&self) -> String {
+        match self.source {
+            Source::Mdc(ref key) => format!("MDC key `{}` not present", key),
+            Source::Module => "module path not present".to_owned(),
+            Source::File => "file not present".to_owned(),
+            Source::Line => "line not present".to_owned(),
+            Source::Variable(_, ref name) => format!("variable `{}` not present", name),
+            Source::Level | Source::Target | Source::Date { .. } => unreachable!(),
+        }
+    }
+}
+
+fn apply_format(format: Option<Format>, value: String) -> String {
+    let format = match format {
+        Some(format) => format,
+        None => return value,
+    };
+
+    let mut len = value.chars().count();
+    let mut value = match format.max {
+        Some(max) if len > max => {
+            let skip = match format.align {
+                Alignment::Left => 0,
+                Alignment::Right => len - max,
+            };
+            len = max;
+            value.chars().skip(skip).take(max).collect()
+        }
+        _ => value,
+    };
+
+    if let Some(min) = format.min {
+        if len < min {
+            let padding = format.fill.to_string().repeat(min - len);
+            match format.align {
+                Alignment::Left => value.push_str(&padding),
+                Alignment::Right => value.insert_str(0, &padding),
+            }
+        }
+    }
+
+    value
+}
+
+fn env_var(params: &[&str], s: &str) -> Result<String, Box<Error + Sync + Send>> {
+    if params.is_empty() || params.len() > 2 {
+        return Err(format!("expected 1 or 2 arguments: `{}`", s).into());
+    }
+    match (env::var(params[0]), params.get(1)) {
+        (Ok(v), _) => Ok(v),
+        (Err(_), Some(&v)) => Ok(v.to_owned()),
+        (Err(_), None) => Err(format!("environment variable `{}` not present", params[0]).into()),
     }
 }
 
 #[derive(PartialOrd, Ord, PartialEq, Eq)]
 enum Chunk {
     Text(String),
-    Mdc {
-        key: String,
-        default: Option<String>,
-    },
+    // an index into the template's arguments
+    Arg(usize),
 }
 
 enum ValueTemplate {
@@ -140,19 +458,24 @@ impl Ord for ValueTemplate {
 }
 
 impl ValueTemplate {
-    fn new(value: &Value) -> Result<ValueTemplate, Box<Error + Sync + Send>> {
+    fn new(
+        value: &Value,
+        builder: &mut Builder,
+    ) -> Result<ValueTemplate, Box<Error + Sync + Send>> {
         let value = match *value {
             Value::Map(ref m) => {
                 let mut m2 = BTreeMap::new();
                 for (k, v) in m {
-                    m2.insert(ValueTemplate::new(k)?, ValueTemplate::new(v)?);
+                    m2.insert(ValueTemplate::new(k, builder)?, ValueTemplate::new(v, builder)?);
                 }
                 ValueTemplate::Map(m2)
             }
-            Value::Newtype(ref v) => ValueTemplate::Newtype(Box::new(ValueTemplate::new(v)?)),
+            Value::Newtype(ref v) => {
+                ValueTemplate::Newtype(Box::new(ValueTemplate::new(v, builder)?))
+            }
             Value::Option(ref v) => {
                 let v = match *v {
-                    Some(ref v) => Some(Box::new(ValueTemplate::new(v)?)),
+                    Some(ref v) => Some(Box::new(ValueTemplate::new(v, builder)?)),
                     None => None,
                 };
                 ValueTemplate::Option(v)
@@ -160,30 +483,14 @@ impl ValueTemplate {
             Value::Seq(ref vs) => {
                 let mut vs2 = vec![];
                 for v in vs {
-                    vs2.push(ValueTemplate::new(v)?);
+                    vs2.push(ValueTemplate::new(v, builder)?);
                 }
                 ValueTemplate::Seq(vs2)
             }
             Value::String(ref s) => {
                 let mut chunks = vec![];
                 for piece in Parser::new(s) {
-                    let c = match piece {
-                        Piece::Text(t) => Chunk::Text(t.to_owned()),
-                        Piece::Argument { name: "mdc", args } => {
-                            if args.is_empty() || args.len() > 2 {
-                                return Err(format!("expected 1 or 2 arguments: `{}`", s).into());
-                            }
-                            Chunk::Mdc {
-                                key: args[0].to_owned(),
-                                default: args.get(1).map(|&s| s.to_owned()),
-                            }
-                        }
-                        Piece::Argument { name, .. } => {
-                            return Err(format!("unknown argument `{}`: `{}`", name, s).into());
-                        }
-                        Piece::Error(e) => return Err(format!("{}: `{}`", e, s).into()),
-                    };
-                    chunks.push(c);
+                    chunks.push(builder.chunk(piece, s)?);
                 }
                 ValueTemplate::String(chunks)
             }
@@ -229,56 +536,30 @@ impl ValueTemplate {
         }
     }
 
-    fn keys(&self, keys: &mut HashSet<String>) {
-        match *self {
-            ValueTemplate::Map(ref m) => {
-                for (k, v) in m {
-                    k.keys(keys);
-                    v.keys(keys);
-                }
-            }
-            ValueTemplate::Newtype(ref v) => v.keys(keys),
-            ValueTemplate::Option(ref v) => {
-                if let Some(ref v) = *v {
-                    v.keys(keys);
-                }
-            }
-            ValueTemplate::Seq(ref vs) => {
-                for v in vs {
-                    v.keys(keys);
-                }
-            }
-            ValueTemplate::String(ref chunks) => {
-                for chunk in chunks {
-                    if let Chunk::Mdc { ref key, .. } = *chunk {
-                        keys.insert(key.clone());
-                    }
-                }
-            }
-            _ => {}
-        }
-    }
-
-    fn expand(&self) -> Result<Value, Box<Error + Sync + Send>> {
+    fn expand(
+        &self,
+        args: &[Arg],
+        values: &Values,
+    ) -> Result<Value, Box<Error + Sync + Send>> {
         let v = match *self {
             ValueTemplate::Map(ref m) => {
                 let mut m2 = BTreeMap::new();
                 for (k, v) in m {
-                    m2.insert(k.expand()?, v.expand()?);
+                    m2.insert(k.expand(args, values)?, v.expand(args, values)?);
                 }
                 Value::Map(m2)
             }
-            ValueTemplate::Newtype(ref v) => Value::Newtype(Box::new(v.expand()?)),
+            ValueTemplate::Newtype(ref v) => Value::Newtype(Box::new(v.expand(args, values)?)),
             ValueTemplate::Option(ref v) => {
                 match *v {
-                    Some(ref v) => Value::Option(Some(Box::new(v.expand()?))),
+                    Some(ref v) => Value::Option(Some(Box::new(v.expand(args, values)?))),
                     None => Value::Option(None),
                 }
             }
             ValueTemplate::Seq(ref vs) => {
                 let mut vs2 = Vec::with_capacity(vs.len());
                 for v in vs {
-                    vs2.push(v.expand()?);
+                    vs2.push(v.expand(args, values)?);
                 }
                 Value::Seq(vs2)
             }
@@ -287,15 +568,10 @@ impl ValueTemplate {
                 for chunk in chunks {
                     match *chunk {
                         Chunk::Text(ref t) => s.push_str(t),
-                        Chunk::Mdc { ref key, ref default } => {
-                            log_mdc::get(key, |v| match (v, default.as_ref().map(|s| &**s)) {
-                                (Some(v), _) | (None, Some(v)) => {
-                                    s.push_str(v);
-                                    Ok(())
-                                }
-                                (None, None) => Err(format!("MDC key `{}` not present", key)),
-                            })?
-                        }
+                        Chunk::Arg(idx) => match values.0[idx] {
+                            Some(ref v) => s.push_str(v),
+                            None => return Err(args[idx].missing().into()),
+                        },
                     }
                 }
                 Value::String(s)
@@ -0,0 +1,218 @@
+//! A router which limits the rate of log events sent to each route of another router.
+//!
+//! Each appender selected by the wrapped router has its own token bucket, kept alongside it in the
+//! cache. Log events arriving while a route's bucket is empty are dropped. The next log event sent
+//! to the route is preceded by a `WARN` log event reporting the number of log events suppressed. If
+//! that report can't be appended, the log event is still sent, and the suppressed log events are
+//! reported again along with the next one. The same goes if an enclosing router discards the log
+//! event.
+use antidote::Mutex;
+use log::{Level, Record};
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use RouterConfig;
+use route::{self, Appender, Cache, Report, Route};
+
+/// Configuration for the `RateLimitRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRouterConfig {
+    router: RouterConfig,
+    rate: f64,
+    burst: Option<u64>,
+}
+
+// The token bucket of a single route.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    suppressed: u64,
+}
+
+// The suppressed log events taken from a route's bucket to be reported along with the next log
+// event. They're returned to the bucket if the report is never appended.
+struct Suppressed {
+    buckets: Arc<Mutex<HashMap<usize, Bucket>>>,
+    id: usize,
+    count: u64,
+    reported: AtomicBool,
+}
+
+impl Report for Suppressed {
+    fn append(&self, appender: &Append, record: &Record) -> Result<(), Box<Error + Sync + Send>> {
+        let plural = if self.count == 1 { "" } else { "s" };
+        appender.append(&Record::builder()
+            .args(format_args!("{} record{} suppressed", self.count, plural))
+            .level(Level::Warn)
+            .target(record.target())
+            .build())?;
+        self.reported.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Drop for Suppressed {
+    fn drop(&mut self) {
+        if self.reported.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(bucket) = self.buckets.lock().get_mut(&self.id) {
+            bucket.suppressed += self.count;
+        }
+    }
+}
+
+/// A router which rate limits the log events routed by another router.
+pub struct RateLimitRouter {
+    // keys this router's token buckets in appender extensions
+    id: usize,
+    router: Box<Route>,
+    rate: f64,
+    burst: f64,
+}
+
+impl fmt::Debug for RateLimitRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RateLimitRouter")
+            .field("router", &self.router)
+            .field("rate", &self.rate)
+            .field("burst", &self.burst)
+            .finish()
+    }
+}
+
+impl RateLimitRouter {
+    // Returns the appender if the log event should be sent to it. If log events have been
+    // suppressed since the last one allowed, a report of them is attached to the appender.
+    fn take(&self, appender: Appender, now: Instant) -> Option<Appender> {
+        // nested rate limiters select the same appenders, so each keeps its own buckets for them
+        let buckets = appender.extension(|| Mutex::new(HashMap::<usize, Bucket>::new()));
+        let mut guard = buckets.lock();
+        let bucket = guard.entry(self.id).or_insert_with(|| Bucket {
+            tokens: self.burst,
+            updated: now,
+            suppressed: 0,
+        });
+
+        let elapsed = now.duration_since(bucket.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens < 1. {
+            bucket.suppressed += 1;
+            return None;
+        }
+        bucket.tokens -= 1.;
+
+        if bucket.suppressed == 0 {
+            return Some(appender);
+        }
+        let report = Suppressed {
+            buckets: buckets.clone(),
+            id: self.id,
+            count: bucket.suppressed,
+            reported: AtomicBool::new(false),
+        };
+        bucket.suppressed = 0;
+        Some(appender.with_report(Arc::new(report)))
+    }
+}
+
+impl Route for RateLimitRouter {
+    /// Returns the first appender selected which is not being rate limited.
+    ///
+    /// An error is returned if the log event was suppressed.
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let mut appenders = vec![];
+        self.route_all(record, cache, &mut appenders)?;
+        match appenders.into_iter().next() {
+            Some(appender) => Ok(appender),
+            None => Err("log event suppressed by rate limit".into()),
+        }
+    }
+
+    fn route_all(
+        &self,
+        record: &Record,
+        cache: &Cache,
+        appenders: &mut Vec<Appender>,
+    ) -> Result<(), Box<Error + Sync + Send>> {
+        let mut routed = vec![];
+        let result = self.router.route_all(record, cache, &mut routed);
+
+        let now = cache.now();
+        for appender in routed {
+            if let Some(appender) = self.take(appender, now) {
+                appenders.push(appender);
+            }
+        }
+        result
+    }
+}
+
+/// A deserializer for the `RateLimitRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: rate_limit
+///
+/// # The router whose routes are rate limited. Required.
+/// router:
+///   kind: pattern
+///   pattern:
+///     kind: file
+///     path: "logs/${mdc(job_id)}.log"
+///
+/// # The sustained number of log events per second allowed for each route.
+/// # Required.
+/// rate: 100
+///
+/// # The number of log events which may be sent to a route in a burst before it
+/// # is limited to `rate`. Defaults to `rate`, rounded up, or 1 if that is
+/// # larger.
+/// burst: 1000
+/// ```
+pub struct RateLimitRouterDeserializer;
+
+impl Deserialize for RateLimitRouterDeserializer {
+    type Trait = Route;
+    type Config = RateLimitRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: RateLimitRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<Route>, Box<Error + Sync + Send>> {
+        if config.rate.is_nan() || config.rate <= 0. {
+            return Err(format!("invalid rate `{}`", config.rate).into());
+        }
+        let burst = match config.burst {
+            Some(0) => return Err("burst must be positive".into()),
+            Some(burst) => burst,
+            None => cmp::max(config.rate.ceil() as u64, 1),
+        };
+
+        let router = deserializers.deserialize(&config.router.kind, config.router.config)?;
+
+        Ok(Box::new(RateLimitRouter {
+            id: route::router_id(),
+            router: router,
+            rate: config.rate,
+            burst: burst as f64,
+        }))
+    }
+}
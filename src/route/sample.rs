@@ -0,0 +1,231 @@
+//! A router which limits the number of log events sent to each route of another router.
+//!
+//! Each appender selected by the wrapped router is sampled independently, with its sampling state
+//! kept alongside it in the cache. Either only the first log events in each time window are kept,
+//! or a random fraction of them. Log events which are not kept are dropped, or sent to a summary
+//! appender if one is configured.
+use antidote::Mutex;
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+use {AppenderConfig, RouterConfig};
+use route::{self, Appender, Cache, Entry, Route};
+
+/// Configuration for the `SampleRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SampleRouterConfig {
+    router: RouterConfig,
+    limit: Option<u64>,
+    #[serde(deserialize_with = "::de_duration", default)]
+    window: Option<Duration>,
+    fraction: Option<f64>,
+    summary: Option<AppenderConfig>,
+}
+
+enum Mode {
+    Limit { limit: u64, window: Duration },
+    Fraction(f64),
+}
+
+// The sampling state of a single route.
+struct Sample {
+    start: Instant,
+    count: u64,
+    rng: u64,
+}
+
+impl Sample {
+    fn new(now: Instant) -> Sample {
+        // the standard library's randomly keyed hasher is a convenient source of a seed
+        let seed = RandomState::new().build_hasher().finish();
+        Sample {
+            start: now,
+            count: 0,
+            rng: seed | 1,
+        }
+    }
+
+    // xorshift64*
+    fn next_f64(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let value = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A router which samples the log events routed by another router.
+pub struct SampleRouter {
+    // keys this router's sampling state in appender extensions
+    id: usize,
+    deserializers: Deserializers,
+    router: Box<Route>,
+    mode: Mode,
+    summary: Option<AppenderConfig>,
+}
+
+impl fmt::Debug for SampleRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SampleRouter")
+            .field("router", &self.router)
+            .finish()
+    }
+}
+
+impl SampleRouter {
+    fn keep(&self, appender: &Appender, now: Instant) -> bool {
+        // nested samplers select the same appenders, so each keeps its own state for them
+        let samples = appender.extension(|| Mutex::new(HashMap::<usize, Sample>::new()));
+        let mut samples = samples.lock();
+        let sample = samples.entry(self.id).or_insert_with(|| Sample::new(now));
+        match self.mode {
+            Mode::Limit { limit, window } => {
+                if now.duration_since(sample.start) >= window {
+                    sample.start = now;
+                    sample.count = 0;
+                }
+                sample.count += 1;
+                sample.count <= limit
+            }
+            Mode::Fraction(fraction) => sample.next_f64() < fraction,
+        }
+    }
+
+    fn summary(
+        &self,
+        config: &AppenderConfig,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        match cache.scope("summary").entry(String::new()) {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => e.insert_with(|| {
+                self.deserializers
+                    .deserialize(&config.kind, config.config.clone())
+            }),
+        }
+    }
+}
+
+impl Route for SampleRouter {
+    /// Returns the first appender selected after sampling.
+    ///
+    /// An error is returned if the log event was dropped.
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let mut appenders = vec![];
+        self.route_all(record, cache, &mut appenders)?;
+        match appenders.into_iter().next() {
+            Some(appender) => Ok(appender),
+            None => Err("log event dropped by sampling".into()),
+        }
+    }
+
+    fn route_all(
+        &self,
+        record: &Record,
+        cache: &Cache,
+        appenders: &mut Vec<Appender>,
+    ) -> Result<(), Box<Error + Sync + Send>> {
+        let mut routed = vec![];
+        let result = self.router.route_all(record, &cache.scope("route"), &mut routed);
+
+        let now = cache.now();
+        let mut diverted = false;
+        for appender in routed {
+            if self.keep(&appender, now) {
+                appenders.push(appender);
+            } else {
+                diverted = true;
+            }
+        }
+
+        if diverted {
+            if let Some(ref summary) = self.summary {
+                appenders.push(self.summary(summary, cache)?);
+            }
+        }
+
+        result
+    }
+}
+
+/// A deserializer for the `SampleRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: sample
+///
+/// # The router whose routes are sampled. Required.
+/// router:
+///   kind: pattern
+///   pattern:
+///     kind: file
+///     path: "logs/${mdc(job_id)}.log"
+///
+/// # The maximum number of log events kept for each route in each window.
+/// # Exactly one of `limit` and `fraction` is required.
+/// limit: 1000
+///
+/// # The length of the windows. Required with `limit`.
+/// window: 1 minute
+///
+/// # The fraction of log events kept for each route, chosen at random. Between
+/// # 0 and 1.
+/// fraction: 0.1
+///
+/// # The appender which receives the log events which are not kept. If not
+/// # set, they are dropped. Optional.
+/// summary:
+///   kind: file
+///   path: "logs/sampled.log"
+/// ```
+pub struct SampleRouterDeserializer;
+
+impl Deserialize for SampleRouterDeserializer {
+    type Trait = Route;
+    type Config = SampleRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: SampleRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<Route>, Box<Error + Sync + Send>> {
+        let mode = match (config.limit, config.window, config.fraction) {
+            (Some(limit), Some(window), None) => Mode::Limit {
+                limit: limit,
+                window: window,
+            },
+            (Some(_), None, None) => return Err("`window` is required with `limit`".into()),
+            (None, None, Some(fraction)) => {
+                if fraction.is_nan() || fraction < 0. || fraction > 1. {
+                    return Err(format!("invalid fraction `{}`", fraction).into());
+                }
+                Mode::Fraction(fraction)
+            }
+            (None, Some(_), Some(_)) => return Err("`window` is only valid with `limit`".into()),
+            _ => return Err("exactly one of `limit` and `fraction` is required".into()),
+        };
+
+        let router = deserializers.deserialize(&config.router.kind, config.router.config)?;
+
+        Ok(Box::new(SampleRouter {
+            id: route::router_id(),
+            deserializers: deserializers.clone(),
+            router: router,
+            mode: mode,
+            summary: config.summary,
+        }))
+    }
+}
@@ -0,0 +1,124 @@
+//! A router which selects an appender based on the target of a log event.
+//!
+//! Each appender is associated with a module path prefix. A log event is routed to the appender
+//! with the longest prefix matching its target, or to the default appender if there is none.
+//! Prefixes match whole path components, so `server::db` matches the targets `server::db` and
+//! `server::db::pool`, but not `server::dbx`.
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use AppenderConfig;
+use route::{Appender, Cache, Entry, Route};
+
+/// Configuration for the `TargetRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetRouterConfig {
+    targets: HashMap<String, AppenderConfig>,
+    default: Option<AppenderConfig>,
+}
+
+/// A router which selects an appender configuration based on the target of a log event.
+pub struct TargetRouter {
+    deserializers: Deserializers,
+    // the default appender is stored under the empty prefix
+    targets: HashMap<String, AppenderConfig>,
+}
+
+impl fmt::Debug for TargetRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TargetRouter").finish()
+    }
+}
+
+impl TargetRouter {
+    fn find<'a>(&'a self, target: &'a str) -> Option<(&'a str, &'a AppenderConfig)> {
+        let mut prefix = target;
+        loop {
+            if let Some(config) = self.targets.get(prefix) {
+                return Some((prefix, config));
+            }
+            if prefix.is_empty() {
+                return None;
+            }
+            prefix = match prefix.rfind("::") {
+                Some(idx) => &prefix[..idx],
+                None => "",
+            };
+        }
+    }
+}
+
+impl Route for TargetRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let (prefix, config) = match self.find(record.target()) {
+            Some(target) => target,
+            None => return Err(format!("no appender for target `{}`", record.target()).into()),
+        };
+
+        match cache.entry(prefix.to_owned()) {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => e.insert_with(|| {
+                self.deserializers
+                    .deserialize(&config.kind, config.config.clone())
+            }),
+        }
+    }
+}
+
+/// A deserializer for the `TargetRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: target
+///
+/// # A map of module path prefixes to appender configurations. Log events are
+/// # sent to the appender with the longest prefix matching their target.
+/// # Required.
+/// targets:
+///   server::db:
+///     kind: file
+///     path: "logs/db.log"
+///   server::http:
+///     kind: file
+///     path: "logs/http.log"
+///
+/// # The appender used for log events whose target does not match any prefix.
+/// # Optional.
+/// default:
+///   kind: file
+///   path: "logs/server.log"
+/// ```
+pub struct TargetRouterDeserializer;
+
+impl Deserialize for TargetRouterDeserializer {
+    type Trait = Route;
+    type Config = TargetRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: TargetRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<Route>, Box<Error + Sync + Send>> {
+        let mut targets = config.targets;
+        if targets.contains_key("") {
+            return Err("target prefixes must not be empty".into());
+        }
+        if let Some(default) = config.default {
+            targets.insert(String::new(), default);
+        }
+
+        Ok(Box::new(TargetRouter {
+            deserializers: deserializers.clone(),
+            targets: targets,
+        }))
+    }
+}
@@ -0,0 +1,566 @@
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+
+use log::{Level, Record};
+use log4rs::append::Append;
+use log4rs_routing_appender::RoutingAppender;
+use log4rs_routing_appender::clock::ManualClock;
+use log4rs_routing_appender::route::{Appender, Cache, Entry, EvictionReason, Route};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+struct TestAppender {
+    appends: Arc<AtomicUsize>,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<Error + Sync + Send>> {
+        self.appends.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn flush(&self) {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Routes by target, recording each appender construction.
+#[derive(Debug, Default)]
+struct TargetRouter {
+    built: Arc<Mutex<Vec<String>>>,
+    flushes: Arc<AtomicUsize>,
+    delay: Option<Duration>,
+}
+
+impl Route for TargetRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        Ok(cache.entry(record.target().to_owned()).or_insert_with(|| {
+            if let Some(delay) = self.delay {
+                thread::sleep(delay);
+            }
+            self.built.lock().unwrap().push(record.target().to_owned());
+            Box::new(TestAppender {
+                flushes: self.flushes.clone(),
+                ..TestAppender::default()
+            })
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct FailingRouter;
+
+impl Route for FailingRouter {
+    fn route(&self, _: &Record, _: &Cache) -> Result<Appender, Box<Error + Sync + Send>> {
+        Err("no route".into())
+    }
+}
+
+/// Routes by target within a scope named after the level, recording the number of appenders in
+/// the whole cache and in the scope.
+#[derive(Debug, Default)]
+struct ScopedRouter {
+    router: TargetRouter,
+    lens: Arc<Mutex<Vec<(usize, usize)>>>,
+}
+
+impl Route for ScopedRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let level = record.level().to_string();
+        let scoped = cache.scope(&level);
+        let appender = self.router.route(record, &scoped)?;
+        self.lens.lock().unwrap().push((cache.len(), scoped.len()));
+        Ok(appender)
+    }
+}
+
+/// Routes by target, allowing at most two appenders. Appenders for targets starting with `!` fail
+/// to be constructed.
+#[derive(Debug)]
+struct LimitedRouter;
+
+impl Route for LimitedRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        let target = record.target();
+        match cache.entry_limited(target.to_owned(), 2) {
+            Some(Entry::Occupied(e)) => Ok(e.into_value()),
+            Some(Entry::Vacant(e)) => e.insert_with(|| {
+                if target.starts_with('!') {
+                    Err("unable to open file".into())
+                } else {
+                    Ok(Box::new(TestAppender::default()))
+                }
+            }),
+            None => Err("route limit reached".into()),
+        }
+    }
+}
+
+/// Fails to construct every appender, counting the attempts.
+#[derive(Debug, Default)]
+struct BrokenRouter {
+    attempts: Arc<AtomicUsize>,
+}
+
+impl Route for BrokenRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &Cache,
+    ) -> Result<Appender, Box<Error + Sync + Send>> {
+        match cache.entry(record.target().to_owned()) {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => e.insert_with(|| {
+                self.attempts.fetch_add(1, Ordering::SeqCst);
+                Err("unable to open file".into())
+            }),
+        }
+    }
+}
+
+fn log(appender: &RoutingAppender, target: &str) {
+    appender
+        .append(&Record::builder().target(target).build())
+        .unwrap();
+}
+
+#[test]
+fn max_entries_evicts_lru() {
+    let router = TargetRouter::default();
+    let built = router.built.clone();
+    let appender = RoutingAppender::builder()
+        .max_entries(2)
+        .build(Box::new(router));
+
+    log(&appender, "a");
+    log(&appender, "b");
+    log(&appender, "a");
+    log(&appender, "c");
+    log(&appender, "a");
+    log(&appender, "b");
+
+    assert_eq!(*built.lock().unwrap(), ["a", "b", "c", "b"]);
+}
+
+#[test]
+fn flush_reaches_cached_appenders() {
+    let router = TargetRouter::default();
+    let flushes = router.flushes.clone();
+    let appender = RoutingAppender::builder().build(Box::new(router));
+
+    log(&appender, "a");
+    log(&appender, "b");
+    appender.flush();
+
+    assert_eq!(flushes.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn evicted_appenders_are_flushed_and_reported() {
+    let router = TargetRouter::default();
+    let flushes = router.flushes.clone();
+    let evicted = Arc::new(Mutex::new(vec![]));
+    let evicted2 = evicted.clone();
+    let appender = RoutingAppender::builder()
+        .max_entries(1)
+        .on_evict(move |key, _, reason| {
+            evicted2.lock().unwrap().push((key.to_owned(), reason));
+        })
+        .build(Box::new(router));
+
+    log(&appender, "a");
+    log(&appender, "b");
+
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        *evicted.lock().unwrap(),
+        [("a".to_owned(), EvictionReason::Capacity)]
+    );
+}
+
+#[test]
+fn fallback_receives_unroutable_records() {
+    let fallback = TestAppender::default();
+    let appends = fallback.appends.clone();
+    let appender = RoutingAppender::builder()
+        .fallback(Box::new(fallback))
+        .build(Box::new(FailingRouter));
+
+    let record = Record::builder().build();
+    assert!(appender.append(&record).is_err());
+    assert!(appender.append(&record).is_ok());
+    assert_eq!(appends.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn failed_constructions_are_remembered() {
+    let router = BrokenRouter::default();
+    let attempts = router.attempts.clone();
+    let appender = RoutingAppender::builder()
+        .failure_backoff(Duration::from_secs(60))
+        .build(Box::new(router));
+
+    let record = Record::builder().target("a").build();
+    for _ in 0..3 {
+        let err = appender.append(&record).unwrap_err();
+        assert_eq!(err.to_string(), "unable to open file");
+    }
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn remembered_failures_are_limited() {
+    let router = BrokenRouter::default();
+    let attempts = router.attempts.clone();
+    let appender = RoutingAppender::builder()
+        .max_entries(2)
+        .failure_backoff(Duration::from_secs(60))
+        .build(Box::new(router));
+
+    for target in &["a", "b", "c"] {
+        appender.append(&Record::builder().target(target).build()).unwrap_err();
+    }
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+    // the failure for "c" is still remembered, but the oldest, for "a", was forgotten
+    appender.append(&Record::builder().target("c").build()).unwrap_err();
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    appender.append(&Record::builder().target("a").build()).unwrap_err();
+    assert_eq!(attempts.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn failure_backoff_expires() {
+    let clock = ManualClock::new();
+    let router = BrokenRouter::default();
+    let attempts = router.attempts.clone();
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .failure_backoff(Duration::from_secs(5))
+        .build(Box::new(router));
+
+    let record = Record::builder().target("a").build();
+    appender.append(&record).unwrap_err();
+    clock.advance(Duration::from_secs(4));
+    appender.append(&record).unwrap_err();
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+    clock.advance(Duration::from_secs(1));
+    appender.append(&record).unwrap_err();
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn durations_longer_than_uptime() {
+    let forever = Duration::from_secs(!0);
+    let appender = RoutingAppender::builder()
+        .idle_timeout(forever)
+        .failure_backoff(forever)
+        .build(Box::new(TargetRouter::default()));
+
+    log(&appender, "a");
+    log(&appender, "a");
+    assert_eq!(appender.stats().hits(), 1);
+}
+
+#[test]
+fn idle_timeout() {
+    let clock = ManualClock::new();
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .idle_timeout(Duration::from_secs(10))
+        .build(Box::new(TargetRouter::default()));
+
+    log(&appender, "a");
+    clock.advance(Duration::from_secs(9));
+    appender.purge();
+    assert_eq!(appender.stats().size(), 1);
+
+    clock.advance(Duration::from_secs(1));
+    appender.purge();
+    assert_eq!(appender.stats().size(), 0);
+    assert_eq!(appender.stats().idle_evictions(), 1);
+}
+
+#[test]
+fn idle_eviction_order() {
+    let clock = ManualClock::new();
+    let evicted = Arc::new(Mutex::new(vec![]));
+    let evicted2 = evicted.clone();
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .idle_timeout(Duration::from_secs(10))
+        .on_evict(move |key, _, _| evicted2.lock().unwrap().push(key.to_owned()))
+        .build(Box::new(TargetRouter::default()));
+
+    for target in &["a", "b", "c", "a", "d"] {
+        log(&appender, target);
+        clock.advance(Duration::from_secs(1));
+    }
+
+    let routes = appender
+        .routes()
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    assert_eq!(routes, ["b", "c", "a", "d"]);
+
+    // b was last used at 1s and c at 2s
+    clock.advance(Duration::from_secs(7));
+    appender.purge();
+    assert_eq!(*evicted.lock().unwrap(), ["b", "c"]);
+
+    clock.advance(Duration::from_secs(1));
+    appender.purge();
+    assert_eq!(*evicted.lock().unwrap(), ["b", "c", "a"]);
+}
+
+#[test]
+fn stats() {
+    let appender = RoutingAppender::builder()
+        .max_entries(2)
+        .build(Box::new(TargetRouter::default()));
+
+    log(&appender, "a");
+    log(&appender, "a");
+    log(&appender, "b");
+    log(&appender, "c");
+
+    let stats = appender.stats();
+    assert_eq!(stats.size(), 2);
+    assert_eq!(stats.hits(), 1);
+    assert_eq!(stats.misses(), 3);
+    assert_eq!(stats.constructions(), 3);
+    assert_eq!(stats.construction_failures(), 0);
+    assert_eq!(stats.idle_evictions(), 0);
+    assert_eq!(stats.capacity_evictions(), 1);
+
+    let routes = appender
+        .routes()
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    assert_eq!(routes, ["b", "c"]);
+}
+
+#[test]
+fn manual_eviction() {
+    let router = TargetRouter::default();
+    let flushes = router.flushes.clone();
+    let appender = RoutingAppender::builder().build(Box::new(router));
+
+    log(&appender, "a");
+    log(&appender, "b");
+    log(&appender, "c");
+
+    assert!(appender.evict("a"));
+    assert!(!appender.evict("a"));
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    assert_eq!(appender.evict_matching(|k| k == "b"), 1);
+    appender.clear();
+    assert_eq!(flushes.load(Ordering::SeqCst), 3);
+
+    let stats = appender.stats();
+    assert_eq!(stats.size(), 0);
+    assert_eq!(stats.manual_evictions(), 3);
+}
+
+#[test]
+fn manual_eviction_ignores_other_evictions() {
+    let appender = Arc::new(
+        RoutingAppender::builder()
+            .max_entries(1)
+            .build(Box::new(TargetRouter::default())),
+    );
+
+    // the logging thread continually evicts appenders for capacity
+    let logger = {
+        let appender = appender.clone();
+        thread::spawn(move || {
+            for i in 0..10000 {
+                log(&appender, &i.to_string());
+            }
+        })
+    };
+    for _ in 0..10000 {
+        assert!(!appender.evict("absent"));
+        assert_eq!(appender.evict_matching(|k| k == "absent"), 0);
+    }
+    logger.join().unwrap();
+}
+
+#[test]
+fn limited_entries() {
+    let clock = ManualClock::new();
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .idle_timeout(Duration::from_secs(10))
+        .shards(4)
+        .build(Box::new(LimitedRouter));
+    let log = |target: &str| {
+        appender
+            .append(&Record::builder().target(target).build())
+            .map_err(|e| e.to_string())
+    };
+
+    // failed constructions don't take up a place
+    assert_eq!(log("!a").unwrap_err(), "unable to open file");
+    log("a").unwrap();
+    log("b").unwrap();
+    log("a").unwrap();
+    assert_eq!(log("c").unwrap_err(), "route limit reached");
+
+    assert!(appender.evict("a"));
+    log("c").unwrap();
+    assert_eq!(log("d").unwrap_err(), "route limit reached");
+
+    // expired appenders in any shard make room
+    clock.advance(Duration::from_secs(10));
+    log("d").unwrap();
+    log("e").unwrap();
+    assert_eq!(log("f").unwrap_err(), "route limit reached");
+}
+
+#[test]
+#[should_panic(expected = "reap interval must be positive")]
+fn zero_reap_interval() {
+    RoutingAppender::builder().reap_interval(Duration::from_secs(0));
+}
+
+#[test]
+fn reaper_purges_idle_appenders() {
+    let router = TargetRouter::default();
+    let flushes = router.flushes.clone();
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_millis(10))
+        .reap_interval(Duration::from_millis(10))
+        .build(Box::new(router));
+
+    log(&appender, "a");
+    for _ in 0..100 {
+        if flushes.load(Ordering::SeqCst) > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    assert_eq!(appender.stats().size(), 0);
+    assert_eq!(appender.stats().idle_evictions(), 1);
+}
+
+#[test]
+fn concurrent_lookups_construct_once() {
+    let router = TargetRouter {
+        delay: Some(Duration::from_millis(100)),
+        ..TargetRouter::default()
+    };
+    let built = router.built.clone();
+    let appender = Arc::new(RoutingAppender::builder().build(Box::new(router)));
+
+    let threads = (0..4)
+        .map(|_| {
+            let appender = appender.clone();
+            thread::spawn(move || log(&appender, "a"))
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(*built.lock().unwrap(), ["a"]);
+    assert_eq!(appender.stats().hits(), 3);
+}
+
+#[test]
+fn sharded_cache() {
+    let router = TargetRouter::default();
+    let built = router.built.clone();
+    let appender = Arc::new(RoutingAppender::builder().shards(4).build(Box::new(router)));
+
+    let threads = (0..4)
+        .map(|i| {
+            let appender = appender.clone();
+            thread::spawn(move || {
+                for j in 0..100 {
+                    log(&appender, &((i + j) % 10).to_string());
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(built.lock().unwrap().len(), 10);
+    let stats = appender.stats();
+    assert_eq!(stats.size(), 10);
+    assert_eq!(stats.misses(), 10);
+    assert_eq!(stats.hits(), 390);
+    assert_eq!(appender.routes().len(), 10);
+
+    assert_eq!(appender.evict_matching(|k| k.parse::<u32>().unwrap() % 2 == 0), 5);
+    assert_eq!(appender.stats().size(), 5);
+}
+
+#[test]
+fn max_entries_applies_across_shards() {
+    let router = TargetRouter::default();
+    let flushes = router.flushes.clone();
+    let appender = RoutingAppender::builder()
+        .max_entries(2)
+        .shards(16)
+        .build(Box::new(router));
+
+    for i in 0..100 {
+        log(&appender, &i.to_string());
+        assert!(appender.stats().size() <= 2);
+    }
+
+    let stats = appender.stats();
+    assert_eq!(stats.size(), 2);
+    assert_eq!(stats.capacity_evictions(), 98);
+    assert_eq!(flushes.load(Ordering::SeqCst), 98);
+
+    let routes = appender
+        .routes()
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    assert_eq!(routes, ["98", "99"]);
+}
+
+#[test]
+fn scoped_len() {
+    let router = ScopedRouter::default();
+    let lens = router.lens.clone();
+    let appender = RoutingAppender::builder().build(Box::new(router));
+
+    for &(target, level) in &[("a", Level::Info), ("b", Level::Info), ("a", Level::Warn)] {
+        appender
+            .append(&Record::builder().target(target).level(level).build())
+            .unwrap();
+    }
+
+    assert_eq!(*lens.lock().unwrap(), [(1, 1), (2, 2), (3, 1)]);
+    let routes = appender.routes().into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(routes, ["INFO:a", "INFO:b", "WARN:a"]);
+}
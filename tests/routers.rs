@@ -0,0 +1,930 @@
+#![cfg(feature = "file")]
+
+#[cfg(feature = "pattern-router")]
+extern crate chrono;
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+#[cfg(feature = "pattern-router")]
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+
+#[cfg(feature = "pattern-router")]
+use chrono::Datelike;
+use log::{Level, Record};
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::{register, RoutingAppender};
+use log4rs_routing_appender::clock::ManualClock;
+use log4rs_routing_appender::route::Route;
+#[cfg(feature = "pattern-router")]
+use log4rs_routing_appender::route::pattern::route_key;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+#[cfg(feature = "pattern-router")]
+use std::env;
+use std::error::Error;
+#[cfg(feature = "pattern-router")]
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+thread_local! {
+    static APPENDS: RefCell<Vec<String>> = RefCell::new(vec![]);
+}
+
+#[derive(Debug)]
+struct TestAppender {
+    key: String,
+    // fails to append log events with a message
+    reject_messages: bool,
+}
+
+impl Append for TestAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<Error + Sync + Send>> {
+        let message = record.args().to_string();
+        let append = if message.is_empty() {
+            self.key.clone()
+        } else if self.reject_messages {
+            return Err("message rejected".into());
+        } else {
+            format!("{}: {}", self.key, message)
+        };
+        APPENDS.with(|a| a.borrow_mut().push(append));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<Append>, Box<Error + Sync + Send>> {
+        if let Some(delay) = config.get("delay_ms") {
+            thread::sleep(Duration::from_millis(delay.parse()?));
+        }
+        Ok(Box::new(TestAppender {
+            key: config["key"].clone(),
+            reject_messages: config.contains_key("reject_messages"),
+        }))
+    }
+}
+
+fn deserializers() -> Deserializers {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+    d
+}
+
+fn appender(config: &str) -> Box<Append> {
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    deserializers().deserialize("routing", config).unwrap()
+}
+
+fn router(kind: &str, config: &str) -> Box<Route> {
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    deserializers().deserialize(kind, config).unwrap()
+}
+
+fn appends() -> Vec<String> {
+    APPENDS.with(|a| a.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn zero_reap_interval() {
+    let config = r#"
+router:
+  kind: target
+  targets: {}
+cache:
+  reap_interval: 0 seconds
+"#;
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    let err = deserializers()
+        .deserialize::<Append>("routing", config)
+        .unwrap_err();
+    assert_eq!(err.to_string(), "reap_interval must be positive");
+}
+
+#[test]
+fn level() {
+    let appender = appender(
+        r#"
+router:
+  kind: level
+  levels:
+    error:
+      kind: test
+      key: error
+    info:
+      kind: test
+      key: info
+  default:
+    kind: test
+    key: default
+"#,
+    );
+
+    for &level in &[Level::Error, Level::Warn, Level::Info, Level::Debug] {
+        appender
+            .append(&Record::builder().level(level).build())
+            .unwrap();
+    }
+
+    assert_eq!(appends(), ["error", "info", "info", "default"]);
+}
+
+#[test]
+fn target() {
+    let appender = appender(
+        r#"
+router:
+  kind: target
+  targets:
+    server::db:
+      kind: test
+      key: db
+    server::db::pool:
+      kind: test
+      key: pool
+  default:
+    kind: test
+    key: default
+"#,
+    );
+
+    for target in &["server::db", "server::db::pool::conn", "server::db::query", "server::dbx"] {
+        appender
+            .append(&Record::builder().target(target).build())
+            .unwrap();
+    }
+
+    assert_eq!(appends(), ["db", "pool", "db", "default"]);
+}
+
+#[test]
+fn multi() {
+    let appender = appender(
+        r#"
+router:
+  kind: multi
+  routers:
+    - kind: target
+      targets:
+        a:
+          kind: test
+          key: a1
+    - kind: target
+      targets:
+        a:
+          kind: test
+          key: a2
+    - kind: level
+      levels:
+        error:
+          kind: test
+          key: error
+"#,
+    );
+
+    appender
+        .append(&Record::builder().target("a").level(Level::Error).build())
+        .unwrap();
+    assert_eq!(appends(), ["a1", "a2", "error"]);
+
+    appender
+        .append(&Record::builder().target("a").level(Level::Info).build())
+        .unwrap();
+    assert_eq!(appends(), ["a1", "a2"]);
+
+    appender
+        .append(&Record::builder().target("b").level(Level::Info).build())
+        .unwrap_err();
+    assert_eq!(appends(), Vec::<String>::new());
+}
+
+fn multi_policy(policy: &str, records: &[(&str, Level)]) -> Vec<(bool, Vec<String>)> {
+    let appender = appender(&format!(
+        r#"
+router:
+  kind: multi
+  policy: {}
+  routers:
+    - kind: target
+      targets:
+        a:
+          kind: test
+          key: a
+    - kind: level
+      levels:
+        error:
+          kind: test
+          key: error
+"#,
+        policy
+    ));
+
+    records
+        .iter()
+        .map(|&(target, level)| {
+            let record = Record::builder().target(target).level(level).build();
+            let ok = appender.append(&record).is_ok();
+            (ok, appends())
+        })
+        .collect()
+}
+
+#[test]
+fn multi_policies() {
+    let records = [("a", Level::Info), ("b", Level::Error), ("b", Level::Info)];
+    let none = Vec::<String>::new;
+
+    assert_eq!(
+        multi_policy("fail_fast", &records),
+        [(false, vec!["a".to_owned()]), (false, none()), (false, none())]
+    );
+    assert_eq!(
+        multi_policy("best_effort", &records),
+        [(true, vec!["a".to_owned()]), (true, vec!["error".to_owned()]), (false, none())]
+    );
+    assert_eq!(
+        multi_policy("require_all", &records),
+        [(false, none()), (false, none()), (false, none())]
+    );
+}
+
+#[test]
+#[cfg(feature = "match-router")]
+fn match_rules() {
+    let appender = appender(
+        r#"
+router:
+  kind: match
+  rules:
+    - target: server::jobs
+      mdc_present: [job]
+      router:
+        kind: pattern
+        pattern:
+          kind: test
+          key: "job-${mdc(job)}"
+    - target: server::http
+      mdc:
+        tenant: "^[a-z]+$"
+      appender:
+        kind: test
+        key: "tenant-${mdc(tenant)}"
+    - level: warn
+      appender:
+        kind: test
+        key: warn
+"#,
+    );
+
+    let log = |target: &str, level: Level| {
+        appender.append(&Record::builder().target(target).level(level).build())
+    };
+
+    log_mdc::insert("job", "1");
+    log_mdc::insert("tenant", "acme");
+    log("server::jobs::runner", Level::Info).unwrap();
+    log("server::http", Level::Info).unwrap();
+    log("server::httpd", Level::Warn).unwrap();
+    log("server::httpd", Level::Info).unwrap_err();
+
+    log_mdc::remove("job");
+    log_mdc::insert("tenant", "ACME");
+    log("server::jobs", Level::Error).unwrap();
+    log("server::http", Level::Info).unwrap_err();
+    log_mdc::remove("tenant");
+
+    assert_eq!(appends(), ["job-1", "tenant-acme", "warn", "warn"]);
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn shard() {
+    let appender = appender(
+        r#"
+router:
+  kind: shard
+  key: "${mdc(tenant)}"
+  shards: 4
+  pattern:
+    kind: test
+    key: "shard-${shard}-${level}"
+"#,
+    );
+
+    // shard assignments must not change between releases
+    for tenant in &["acme", "globex", "initech", "umbrella", "acme"] {
+        log_mdc::insert("tenant", *tenant);
+        appender.append(&Record::builder().build()).unwrap();
+    }
+    log_mdc::remove("tenant");
+    appender.append(&Record::builder().build()).unwrap_err();
+
+    assert_eq!(
+        appends(),
+        [
+            "shard-3-INFO",
+            "shard-2-INFO",
+            "shard-1-INFO",
+            "shard-1-INFO",
+            "shard-3-INFO",
+        ]
+    );
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn map() {
+    let appender = appender(
+        r#"
+router:
+  kind: map
+  key: "${mdc(tenant)}"
+  values:
+    acme:
+      kind: test
+      key: acme
+    globex:
+      kind: test
+      key: globex
+  default:
+    kind: test
+    key: default
+"#,
+    );
+
+    for tenant in &["acme", "globex", "initech", "acme"] {
+        log_mdc::insert("tenant", *tenant);
+        appender.append(&Record::builder().build()).unwrap();
+    }
+    log_mdc::remove("tenant");
+    appender.append(&Record::builder().build()).unwrap();
+
+    assert_eq!(appends(), ["acme", "globex", "default", "acme", "default"]);
+}
+
+#[test]
+fn sample_limit() {
+    let clock = ManualClock::new();
+    let router = router(
+        "sample",
+        r#"
+router:
+  kind: target
+  targets:
+    a:
+      kind: test
+      key: a
+    b:
+      kind: test
+      key: b
+limit: 2
+window: 1 minute
+summary:
+  kind: test
+  key: summary
+"#,
+    );
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .build(router);
+
+    for target in &["a", "a", "b", "a", "a", "b"] {
+        appender
+            .append(&Record::builder().target(target).build())
+            .unwrap();
+    }
+    assert_eq!(appends(), ["a", "a", "b", "summary", "summary", "b"]);
+
+    clock.advance(Duration::from_secs(60));
+    appender
+        .append(&Record::builder().target("a").build())
+        .unwrap();
+    assert_eq!(appends(), ["a"]);
+}
+
+#[test]
+fn nested_samples() {
+    let appender = appender(
+        r#"
+router:
+  kind: sample
+  router:
+    kind: sample
+    router:
+      kind: target
+      targets:
+        a:
+          kind: test
+          key: a
+    limit: 2
+    window: 1 minute
+  limit: 3
+  window: 1 minute
+"#,
+    );
+
+    // each sampler counts the log events it sees separately
+    for _ in 0..4 {
+        let _ = appender.append(&Record::builder().target("a").build());
+    }
+    assert_eq!(appends(), ["a", "a"]);
+}
+
+#[test]
+fn sample_fraction() {
+    let config = |fraction| {
+        format!(
+            r#"
+router:
+  kind: sample
+  router:
+    kind: level
+    levels:
+      trace:
+        kind: test
+        key: kept
+  fraction: {}
+"#,
+            fraction
+        )
+    };
+
+    let none = appender(&config(0.));
+    for _ in 0..10 {
+        none.append(&Record::builder().build()).unwrap();
+    }
+    assert!(appends().is_empty());
+
+    let all = appender(&config(1.));
+    for _ in 0..10 {
+        all.append(&Record::builder().build()).unwrap();
+    }
+    assert_eq!(appends().len(), 10);
+}
+
+#[test]
+fn rate_limit() {
+    let clock = ManualClock::new();
+    let router = router(
+        "rate_limit",
+        r#"
+router:
+  kind: target
+  targets:
+    a:
+      kind: test
+      key: a
+    b:
+      kind: test
+      key: b
+rate: 2
+burst: 2
+"#,
+    );
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .build(router);
+    let log = |target: &str| {
+        appender
+            .append(&Record::builder().target(target).build())
+            .unwrap();
+    };
+
+    for target in &["a", "a", "a", "a", "b"] {
+        log(target);
+    }
+    assert_eq!(appends(), ["a", "a", "b"]);
+
+    clock.advance(Duration::from_millis(500));
+    log("a");
+    log("a");
+    assert_eq!(appends(), ["a: 2 records suppressed", "a"]);
+
+    clock.advance(Duration::from_secs(10));
+    log("a");
+    log("a");
+    log("a");
+    assert_eq!(appends(), ["a: 1 record suppressed", "a", "a"]);
+}
+
+#[test]
+fn rate_limit_report_failure() {
+    let clock = ManualClock::new();
+    let router = router(
+        "rate_limit",
+        r#"
+router:
+  kind: multi
+  routers:
+    - kind: target
+      targets:
+        a:
+          kind: test
+          key: a
+          reject_messages: "true"
+    - kind: target
+      targets:
+        a:
+          kind: test
+          key: b
+rate: 1
+burst: 1
+"#,
+    );
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .build(router);
+    let log = || appender.append(&Record::builder().target("a").build());
+
+    log().unwrap();
+    log().unwrap();
+    assert_eq!(appends(), ["a", "b"]);
+
+    // the report to the first appender fails, but the log event is still sent to both
+    clock.advance(Duration::from_secs(1));
+    log().unwrap_err();
+    assert_eq!(appends(), ["a", "b: 1 record suppressed", "b"]);
+
+    // the suppressed log event is still waiting to be reported to the first appender
+    clock.advance(Duration::from_secs(1));
+    log().unwrap_err();
+    assert_eq!(appends(), ["a", "b"]);
+}
+
+#[test]
+fn rate_limit_report_discarded() {
+    let clock = ManualClock::new();
+    let router = router(
+        "multi",
+        r#"
+policy: require_all
+routers:
+  - kind: rate_limit
+    router:
+      kind: target
+      targets:
+        a:
+          kind: test
+          key: a
+    rate: 1
+    burst: 1
+  - kind: target
+    targets:
+      a::x:
+        kind: test
+        key: x
+"#,
+    );
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .build(router);
+    let log = |target: &str| appender.append(&Record::builder().target(target).build());
+
+    log("a::x").unwrap();
+    log("a::x").unwrap();
+    assert_eq!(appends(), ["a", "x", "x"]);
+
+    // the second router fails, so the log event and its report are discarded
+    clock.advance(Duration::from_secs(1));
+    log("a").unwrap_err();
+    assert_eq!(appends(), Vec::<String>::new());
+
+    clock.advance(Duration::from_secs(1));
+    log("a::x").unwrap();
+    assert_eq!(appends(), ["a: 1 record suppressed", "a", "x"]);
+}
+
+#[test]
+fn nested_rate_limits() {
+    let clock = ManualClock::new();
+    let router = router(
+        "rate_limit",
+        r#"
+router:
+  kind: rate_limit
+  router:
+    kind: target
+    targets:
+      a:
+        kind: test
+        key: a
+  rate: 1
+  burst: 2
+rate: 1
+burst: 2
+"#,
+    );
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .build(router);
+
+    // each rate limiter has its own bucket
+    for _ in 0..3 {
+        appender
+            .append(&Record::builder().target("a").build())
+            .unwrap();
+    }
+    assert_eq!(appends(), ["a", "a"]);
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_record_fields() {
+    let appender = appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${target}/${level}/${line(none)}"
+"#,
+    );
+
+    appender
+        .append(&Record::builder().target("a").level(Level::Info).build())
+        .unwrap();
+    appender
+        .append(&Record::builder().target("b").level(Level::Warn).line(Some(10)).build())
+        .unwrap();
+    appender
+        .append(&Record::builder().target("a").level(Level::Info).build())
+        .unwrap();
+
+    assert_eq!(appends(), ["a/INFO/none", "b/WARN/10", "a/INFO/none"]);
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_date() {
+    let appender = appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${d(%Y)(utc)}"
+"#,
+    );
+
+    let before = chrono::Utc::now().year();
+    appender.append(&Record::builder().build()).unwrap();
+    let after = chrono::Utc::now().year();
+
+    let appends = appends();
+    assert!(appends == [before.to_string()] || appends == [after.to_string()]);
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_default_date() {
+    let appender = appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${d}"
+"#,
+    );
+
+    let before = chrono::Local::now().format("%Y-%m-%d").to_string();
+    appender.append(&Record::builder().build()).unwrap();
+    appender.append(&Record::builder().build()).unwrap();
+    let after = chrono::Local::now().format("%Y-%m-%d").to_string();
+
+    let appends = appends();
+    assert_eq!(appends.len(), 2);
+    assert!(appends[0] == before || appends[0] == after);
+    assert_eq!(appends[0], appends[1]);
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_env() {
+    env::set_var("ROUTING_APPENDER_TEST_ENV", "prod");
+    let appender = appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${env(ROUTING_APPENDER_TEST_ENV)}-${env(ROUTING_APPENDER_TEST_MISSING)(dev)}"
+"#,
+    );
+
+    appender.append(&Record::builder().build()).unwrap();
+
+    assert_eq!(appends(), ["prod-dev"]);
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_path_sanitization() {
+    let appender = appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${mdc(job):path}|${mdc(job)}"
+"#,
+    );
+
+    for job in &["../etc", "..", "a%b\n"] {
+        log_mdc::insert("job", *job);
+        appender.append(&Record::builder().build()).unwrap();
+    }
+    log_mdc::remove("job");
+
+    assert_eq!(
+        appends(),
+        ["..%2Fetc|../etc", "%2E%2E|..", "a%25b%0A|a%b\n"]
+    );
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_format() {
+    let appender = appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${mdc(job):.4}|${mdc(job):>.4}|${mdc(job):-<6}|${line(0):0>3}|${mdc(job):path:.4}"
+"#,
+    );
+
+    log_mdc::insert("job", "a/b");
+    appender
+        .append(&Record::builder().line(Some(7)).build())
+        .unwrap();
+    log_mdc::insert("job", "abcdefgh");
+    appender.append(&Record::builder().build()).unwrap();
+    log_mdc::remove("job");
+
+    assert_eq!(
+        appends(),
+        ["a/b|a/b|a/b---|007|a%2Fb", "abcd|efgh|abcdefgh|000|abcd"]
+    );
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_format_before_sanitization() {
+    let appender = appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${mdc(job):path:.2}|${mdc(job):path:.3}"
+"#,
+    );
+
+    // truncation must not produce `..` or cut an escape sequence in half
+    log_mdc::insert("job", "..x");
+    appender.append(&Record::builder().build()).unwrap();
+    log_mdc::insert("job", "a/bc");
+    appender.append(&Record::builder().build()).unwrap();
+    log_mdc::remove("job");
+
+    assert_eq!(appends(), ["%2E%2E|..x", "a%2F|a%2Fb"]);
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_route_keys() {
+    let router = router(
+        "pattern",
+        r#"
+pattern:
+  kind: test
+  key: "${mdc(user)}/${mdc(job)(no_job)}"
+"#,
+    );
+    let appender = RoutingAppender::builder().build(router);
+
+    log_mdc::insert("user", "sfackler");
+    appender.append(&Record::builder().build()).unwrap();
+    log_mdc::remove("user");
+    appends();
+
+    let routes = appender.routes().into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(routes, ["8sfackler6no_job"]);
+    assert!(appender.evict(&route_key(vec![Some("sfackler"), Some("no_job")])));
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_max_routes() {
+    let appender = appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${target}"
+  max_routes: 2
+  overflow: other
+"#,
+    );
+
+    for target in &["a", "b", "c", "a", "d"] {
+        appender
+            .append(&Record::builder().target(target).build())
+            .unwrap();
+    }
+
+    assert_eq!(appends(), ["a", "b", "other", "a", "other"]);
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_overflow_route_is_not_counted() {
+    let clock = ManualClock::new();
+    let router = router(
+        "pattern",
+        r#"
+pattern:
+  kind: test
+  key: "${target}"
+max_routes: 2
+overflow: other
+"#,
+    );
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .idle_timeout(Duration::from_secs(10))
+        .build(router);
+    let log = |target: &str| {
+        appender
+            .append(&Record::builder().target(target).build())
+            .unwrap();
+    };
+
+    log("a");
+    log("b");
+    clock.advance(Duration::from_secs(5));
+    log("a");
+    log("c");
+    clock.advance(Duration::from_secs(5));
+    // "b" has expired, and the overflow route doesn't take up its place
+    log("d");
+
+    assert_eq!(appends(), ["a", "b", "a", "other", "d"]);
+}
+
+#[test]
+#[cfg(feature = "pattern-router")]
+fn pattern_max_routes_concurrent() {
+    let appender = Arc::new(appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${target}"
+    delay_ms: "20"
+  max_routes: 2
+"#,
+    ));
+
+    let barrier = Arc::new(Barrier::new(8));
+    let threads = (0..8)
+        .map(|i| {
+            let appender = appender.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let target = i.to_string();
+                barrier.wait();
+                appender
+                    .append(&Record::builder().target(&target).build())
+                    .is_ok()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let routed = threads
+        .into_iter()
+        .map(|t| t.join().unwrap())
+        .filter(|&ok| ok)
+        .count();
+    assert_eq!(routed, 2);
+}